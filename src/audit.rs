@@ -0,0 +1,142 @@
+use std::{
+    env,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use rocket::fairing::{AdHoc, Fairing};
+use serde::Serialize;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::model::read::Environment;
+
+/// A change an admin made to a user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", content = "environment", rename_all = "snake_case")]
+pub enum AuditAction {
+    CreateUser,
+    UpdateUser,
+    DeleteUser,
+    GrantEnvironment(Environment),
+    RevokeEnvironment(Environment),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    /// The admin that made the change.
+    pub actor: Uuid,
+    #[serde(flatten)]
+    pub action: AuditAction,
+    /// The user that was changed.
+    pub target: Uuid,
+}
+
+/// Where the changes admins make are recorded, for compliance.
+pub trait AuditLog: Send + Sync {
+    fn record(&self, actor: Uuid, action: AuditAction, target: Uuid);
+}
+
+/// The audit log managed by rocket.
+pub type Audit = Box<dyn AuditLog>;
+
+/// Keeps the entries in memory only, for tests and local development.
+#[derive(Debug, Default)]
+pub struct InMemoryAuditLog {
+    entries: Mutex<Vec<AuditEntry>>,
+}
+
+impl InMemoryAuditLog {
+    #[allow(unused)]
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        self.entries
+            .lock()
+            .expect("audit log lock poisoned")
+            .clone()
+    }
+}
+
+impl AuditLog for InMemoryAuditLog {
+    fn record(&self, actor: Uuid, action: AuditAction, target: Uuid) {
+        self.entries
+            .lock()
+            .expect("audit log lock poisoned")
+            .push(AuditEntry {
+                at: Utc::now(),
+                actor,
+                action,
+                target,
+            });
+    }
+}
+
+/// Writes every entry as a line of JSON to stdout, for the log collector to pick up.
+#[derive(Debug, Default)]
+pub struct StdoutAuditLog;
+
+impl AuditLog for StdoutAuditLog {
+    fn record(&self, actor: Uuid, action: AuditAction, target: Uuid) {
+        let entry = AuditEntry {
+            at: Utc::now(),
+            actor,
+            action,
+            target,
+        };
+        match serde_json::to_string(&entry) {
+            Ok(line) => println!("{line}"),
+            Err(err) => error!(%actor, %target, error = %err, "Failed to write audit entry"),
+        }
+    }
+}
+
+/// Shares a log with whoever else reads it, like a test.
+impl<T: AuditLog + ?Sized> AuditLog for Arc<T> {
+    fn record(&self, actor: Uuid, action: AuditAction, target: Uuid) {
+        (**self).record(actor, action, target)
+    }
+}
+
+/// Writes the audit log to stdout, or keeps it in memory with `AUDIT_LOG=memory`.
+///
+/// A log already given to the rocket is kept.
+pub fn fairing() -> impl Fairing {
+    AdHoc::on_ignite("Audit log", |rocket| async {
+        if rocket.state::<Audit>().is_some() {
+            return rocket;
+        }
+        match env::var("AUDIT_LOG").as_deref() {
+            Ok("memory") => {
+                warn!("AUDIT_LOG is memory, admin actions are not kept");
+                rocket.manage::<Audit>(Box::new(InMemoryAuditLog::default()))
+            }
+            _ => rocket.manage::<Audit>(Box::new(StdoutAuditLog)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_serialize_as_flat_json() {
+        let log = InMemoryAuditLog::default();
+        let (actor, target) = (Uuid::new_v4(), Uuid::new_v4());
+        log.record(
+            actor,
+            AuditAction::GrantEnvironment(Environment::Prod),
+            target,
+        );
+        log.record(actor, AuditAction::DeleteUser, target);
+
+        let entries = log.entries();
+        let json = serde_json::to_value(&entries[0]).unwrap();
+        assert_eq!(json["action"], "grant_environment");
+        assert_eq!(json["environment"], "prod");
+        assert_eq!(json["actor"], serde_json::json!(actor));
+        let json = serde_json::to_value(&entries[1]).unwrap();
+        assert_eq!(json["action"], "delete_user");
+        assert!(json.get("environment").is_none());
+    }
+}
@@ -0,0 +1,42 @@
+use std::marker::PhantomData;
+
+use rocket::{
+    Request,
+    request::{FromRequest, Outcome},
+};
+
+use crate::authentication::{AccessToken, authenticate, error::AuthError};
+
+/// An audience a route expects instead of the configured one, for example:
+///
+/// ```ignore
+/// pub struct LegacyBilling;
+///
+/// impl RequiredAudience for LegacyBilling {
+///     const AUDIENCE: &'static str = "billing.silenlocatelli.com";
+/// }
+/// ```
+#[allow(unused)]
+pub trait RequiredAudience {
+    const AUDIENCE: &'static str;
+}
+
+/// The access token of a request, issued for the audience `A` rather than the configured one.
+#[allow(unused)]
+pub struct Audience<A: RequiredAudience> {
+    pub token: AccessToken,
+    audience: PhantomData<A>,
+}
+
+#[async_trait]
+impl<'r, A: RequiredAudience> FromRequest<'r> for Audience<A> {
+    type Error = AuthError;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authenticate(request, Some(A::AUDIENCE))
+            .await
+            .map(|token| Audience {
+                token,
+                audience: PhantomData,
+            })
+    }
+}
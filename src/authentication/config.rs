@@ -0,0 +1,423 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::{self, Display},
+    str::FromStr,
+    time::Duration,
+};
+
+use jsonwebtoken::{Algorithm, Validation};
+use rocket::figment::{Figment, providers::Serialized};
+use serde::{Deserialize, Deserializer, de};
+use tracing::warn;
+
+use crate::model::read::Environment;
+
+const PREFIX: &str = "AUTH_";
+
+/// How tokens are validated and where their keys come from, read once at ignite from the
+/// `AUTH_` variables, like `jwks_url` from `AUTH_JWKS_URL`.
+///
+/// Lists are comma separated, so that secrets and keys can be rotated.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// The environment this instance runs in, from `APP_ENV` or else `RUST_ENV`.
+    #[serde(skip)]
+    pub environment: Option<Environment>,
+    #[serde(deserialize_with = "list")]
+    pub jwt_aud: Option<Vec<String>>,
+    #[serde(deserialize_with = "list")]
+    pub jwt_aud_prod: Option<Vec<String>>,
+    #[serde(deserialize_with = "list")]
+    pub jwt_aud_staging: Option<Vec<String>>,
+    #[serde(deserialize_with = "list")]
+    pub jwt_aud_test: Option<Vec<String>>,
+    /// Only disabled for local development against a shared identity provider.
+    #[serde(deserialize_with = "flag")]
+    pub jwt_validate_aud: bool,
+    #[serde(deserialize_with = "list")]
+    pub jwt_iss: Option<Vec<String>>,
+    #[serde(deserialize_with = "parsed")]
+    pub jwt_leeway_secs: u64,
+    #[serde(deserialize_with = "list")]
+    pub required_claims: Option<Vec<String>>,
+    #[serde(deserialize_with = "list")]
+    pub jwks_url: Option<Vec<String>>,
+    /// Read when there is no url or none can be fetched.
+    pub jwks_file: Option<String>,
+    #[serde(deserialize_with = "parsed")]
+    pub jwks_ttl_secs: u64,
+    #[serde(deserialize_with = "parsed")]
+    pub jwks_http_timeout_ms: u64,
+    #[serde(deserialize_with = "parsed")]
+    pub jwks_fetch_retries: u32,
+    #[serde(deserialize_with = "parsed")]
+    pub jwks_fetch_backoff_ms: u64,
+    #[serde(deserialize_with = "parsed")]
+    pub jwks_probe_timeout_ms: u64,
+    #[serde(deserialize_with = "list")]
+    pub hs256_secret: Option<Vec<String>>,
+    pub hs256_secret_file: Option<String>,
+    /// The master secrets the HS256 secrets are derived from with `hs256_info`.
+    #[serde(deserialize_with = "list")]
+    pub hs256_master: Option<Vec<String>>,
+    pub hs256_info: Option<String>,
+    pub ed25519_public_key: Option<String>,
+    pub ed25519_public_key_file: Option<String>,
+    pub rs256_public_key: Option<String>,
+    pub rs256_public_key_file: Option<String>,
+    /// The claim with the permissions, for identity providers that namespace custom claims,
+    /// like `https://outerspace/permissions`.
+    pub permissions_claim: String,
+    pub cookie_name: String,
+    /// Whether tokens may be passed as `?access_token=`, for links that cannot set headers.
+    /// Off by default, as the query string ends up in access logs.
+    #[serde(deserialize_with = "flag")]
+    pub allow_query_token: bool,
+    /// Tokens longer than this are refused before they are parsed.
+    #[serde(deserialize_with = "parsed")]
+    pub max_token_bytes: usize,
+    /// Adds the `X-Auth-Time-Micros` header with how long decoding the token took.
+    #[serde(deserialize_with = "flag")]
+    pub debug_timing: bool,
+    /// The JSON object of the [`RoleMap`](super::roles::RoleMap), read when there is no file.
+    pub role_map: Option<String>,
+    pub role_map_file: Option<String>,
+    #[serde(deserialize_with = "parsed")]
+    pub ratelimit_window_secs: u64,
+    /// Clients sending this many invalid tokens within the window are refused until it has
+    /// passed, off with the default of 0.
+    #[serde(deserialize_with = "parsed")]
+    pub ratelimit_max_failures: u32,
+    /// Whether invalid tokens are counted against the address in rocket's `ip_header`, only for
+    /// a proxy in front that always sets it. Otherwise the address of the peer is used, as
+    /// clients can send the header themselves.
+    #[serde(deserialize_with = "flag")]
+    pub ratelimit_trust_proxy: bool,
+    /// Whether the secrets are checked at ignite by minting and decoding a token.
+    #[serde(deserialize_with = "flag")]
+    pub selftest: bool,
+    /// The path prefixes that never require authentication.
+    #[serde(deserialize_with = "list")]
+    pub public_routes: Option<Vec<String>>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            environment: None,
+            jwt_aud: None,
+            jwt_aud_prod: None,
+            jwt_aud_staging: None,
+            jwt_aud_test: None,
+            jwt_validate_aud: true,
+            jwt_iss: None,
+            jwt_leeway_secs: 0,
+            required_claims: None,
+            jwks_url: None,
+            jwks_file: None,
+            jwks_ttl_secs: 60 * 60,
+            jwks_http_timeout_ms: 5000,
+            jwks_fetch_retries: 3,
+            jwks_fetch_backoff_ms: 500,
+            jwks_probe_timeout_ms: 2000,
+            hs256_secret: None,
+            hs256_secret_file: None,
+            hs256_master: None,
+            hs256_info: None,
+            ed25519_public_key: None,
+            ed25519_public_key_file: None,
+            rs256_public_key: None,
+            rs256_public_key_file: None,
+            permissions_claim: "permissions".to_string(),
+            cookie_name: "access_token".to_string(),
+            allow_query_token: false,
+            max_token_bytes: 8192,
+            debug_timing: false,
+            role_map: None,
+            role_map_file: None,
+            ratelimit_window_secs: 60,
+            ratelimit_max_failures: 0,
+            ratelimit_trust_proxy: false,
+            selftest: false,
+            public_routes: None,
+        }
+    }
+}
+
+/// Like the derived implementation, without the secrets.
+impl fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("environment", &self.environment)
+            .field("jwt_aud", &self.jwt_aud)
+            .field("jwt_aud_prod", &self.jwt_aud_prod)
+            .field("jwt_aud_staging", &self.jwt_aud_staging)
+            .field("jwt_aud_test", &self.jwt_aud_test)
+            .field("jwt_validate_aud", &self.jwt_validate_aud)
+            .field("jwt_iss", &self.jwt_iss)
+            .field("jwt_leeway_secs", &self.jwt_leeway_secs)
+            .field("required_claims", &self.required_claims)
+            .field("jwks_url", &self.jwks_url)
+            .field("jwks_file", &self.jwks_file)
+            .field("jwks_ttl_secs", &self.jwks_ttl_secs)
+            .field("jwks_http_timeout_ms", &self.jwks_http_timeout_ms)
+            .field("jwks_fetch_retries", &self.jwks_fetch_retries)
+            .field("jwks_fetch_backoff_ms", &self.jwks_fetch_backoff_ms)
+            .field("jwks_probe_timeout_ms", &self.jwks_probe_timeout_ms)
+            .field("hs256_secret", &redacted(&self.hs256_secret))
+            .field("hs256_secret_file", &self.hs256_secret_file)
+            .field("hs256_master", &redacted(&self.hs256_master))
+            .field("hs256_info", &self.hs256_info)
+            .field("ed25519_public_key", &self.ed25519_public_key)
+            .field("ed25519_public_key_file", &self.ed25519_public_key_file)
+            .field("rs256_public_key", &self.rs256_public_key)
+            .field("rs256_public_key_file", &self.rs256_public_key_file)
+            .field("permissions_claim", &self.permissions_claim)
+            .field("cookie_name", &self.cookie_name)
+            .field("allow_query_token", &self.allow_query_token)
+            .field("max_token_bytes", &self.max_token_bytes)
+            .field("debug_timing", &self.debug_timing)
+            .field("role_map", &self.role_map)
+            .field("role_map_file", &self.role_map_file)
+            .field("ratelimit_window_secs", &self.ratelimit_window_secs)
+            .field("ratelimit_max_failures", &self.ratelimit_max_failures)
+            .field("ratelimit_trust_proxy", &self.ratelimit_trust_proxy)
+            .field("selftest", &self.selftest)
+            .field("public_routes", &self.public_routes)
+            .finish()
+    }
+}
+
+/// As many `***` as there are secrets.
+fn redacted(secrets: &Option<Vec<String>>) -> Option<Vec<&'static str>> {
+    secrets
+        .as_ref()
+        .map(|secrets| secrets.iter().map(|_| "***").collect())
+}
+
+impl AuthConfig {
+    /// Reads the configuration, failing on values that cannot be parsed.
+    pub fn from_env() -> anyhow::Result<Self> {
+        // The values are kept as strings so a secret like `1234` is not read as a number.
+        let vars: BTreeMap<String, String> = env::vars()
+            .filter_map(|(key, value)| Some((key.strip_prefix(PREFIX)?.to_lowercase(), value)))
+            .collect();
+        let mut config: AuthConfig =
+            Figment::from(Serialized::globals(vars))
+                .extract()
+                .map_err(|err| match err.path.last() {
+                    Some(key) => {
+                        anyhow::anyhow!("invalid {PREFIX}{}: {}", key.to_uppercase(), err.kind)
+                    }
+                    None => anyhow::anyhow!("invalid auth configuration: {}", err.kind),
+                })?;
+        config.environment = env::var("APP_ENV")
+            .or_else(|_| env::var("RUST_ENV"))
+            .ok()
+            .and_then(|value| deployment_environment(&value));
+        Ok(config)
+    }
+
+    /// Checks the options that only make sense together, before any key is loaded.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        // When both the secret and its file are set, the file is read.
+        anyhow::ensure!(
+            self.hs256_master.is_none()
+                || (self.hs256_secret.is_none() && self.hs256_secret_file.is_none()),
+            "AUTH_HS256_MASTER cannot be set with AUTH_HS256_SECRET or AUTH_HS256_SECRET_FILE"
+        );
+        anyhow::ensure!(
+            self.hs256_master.is_none() || self.hs256_info.is_some(),
+            "AUTH_HS256_INFO is not set, it is needed with AUTH_HS256_MASTER"
+        );
+        anyhow::ensure!(
+            self.ed25519_public_key.is_none() || self.ed25519_public_key_file.is_none(),
+            "only one of AUTH_ED25519_PUBLIC_KEY, AUTH_ED25519_PUBLIC_KEY_FILE can be set"
+        );
+        anyhow::ensure!(
+            self.rs256_public_key.is_none() || self.rs256_public_key_file.is_none(),
+            "only one of AUTH_RS256_PUBLIC_KEY, AUTH_RS256_PUBLIC_KEY_FILE can be set"
+        );
+        anyhow::ensure!(
+            !self.jwt_validate_aud || self.audience().is_some_and(|aud| !aud.is_empty()),
+            "no jwt audience configured, set AUTH_JWT_AUD or AUTH_JWT_AUD_<ENV>"
+        );
+        Ok(())
+    }
+
+    /// The audiences tokens must be issued for: those of the deployment environment, like
+    /// `AUTH_JWT_AUD_PROD`, else `AUTH_JWT_AUD` or the default compiled in with
+    /// `AUTH_JWT_AUD_DEFAULT`.
+    pub fn audience(&self) -> Option<Vec<String>> {
+        let for_environment = match self.environment {
+            Some(Environment::Prod) => &self.jwt_aud_prod,
+            Some(Environment::Staging) => &self.jwt_aud_staging,
+            Some(Environment::Test) => &self.jwt_aud_test,
+            None => &None,
+        };
+        for_environment
+            .clone()
+            .or_else(|| self.jwt_aud.clone())
+            .or_else(|| option_env!("AUTH_JWT_AUD_DEFAULT").map(split))
+    }
+
+    /// The claims every token must have, checked by the [`Decoder`](super::Decoder).
+    pub fn required_claims(&self) -> Vec<String> {
+        self.required_claims
+            .clone()
+            .unwrap_or_else(|| vec!["tax_platform_apps".to_string()])
+    }
+
+    pub fn validation(&self, algo: Algorithm) -> Validation {
+        let mut validation = Validation::new(algo);
+        // Without an audience no token is accepted, the fairing refuses to ignite in that case.
+        // jsonwebtoken only checks the audience of tokens that have one, unless it is required.
+        if self.jwt_validate_aud {
+            validation.set_audience(&self.audience().unwrap_or_default());
+            validation.required_spec_claims.insert("aud".into());
+        }
+        validation.leeway = self.jwt_leeway_secs;
+        if let Some(iss) = &self.jwt_iss {
+            validation.set_issuer(iss);
+            validation.required_spec_claims.insert("iss".into());
+        }
+        validation
+    }
+
+    pub fn jwks_ttl(&self) -> Duration {
+        Duration::from_secs(self.jwks_ttl_secs)
+    }
+
+    pub fn ratelimit_window(&self) -> Duration {
+        Duration::from_secs(self.ratelimit_window_secs)
+    }
+}
+
+/// The environment named by `APP_ENV` or `RUST_ENV`, with the usual aliases like `production`.
+/// Any other name, like `development`, has no audience of its own and uses the default one.
+fn deployment_environment(value: &str) -> Option<Environment> {
+    match value.trim().to_lowercase().as_str() {
+        "prod" | "production" => Some(Environment::Prod),
+        "staging" | "stage" => Some(Environment::Staging),
+        "test" | "testing" => Some(Environment::Test),
+        _ => {
+            warn!(
+                environment = value,
+                "unknown deployment environment, using the default jwt audience"
+            );
+            None
+        }
+    }
+}
+
+fn split(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    Ok(Some(split(&String::deserialize(deserializer)?)))
+}
+
+fn parsed<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err: Display>,
+{
+    String::deserialize(deserializer)?
+        .trim()
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+fn flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    match String::deserialize(deserializer)?.trim() {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        other => Err(de::Error::custom(format!(
+            "expected true or false, found '{other}'"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(vars: &[(&str, &str)]) -> anyhow::Result<AuthConfig> {
+        let vars: BTreeMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Ok(Figment::from(Serialized::defaults(vars)).extract()?)
+    }
+
+    #[test]
+    fn values_are_parsed_from_strings() {
+        let config = config(&[
+            ("jwt_aud", "a.example.com, b.example.com"),
+            ("jwt_leeway_secs", "30"),
+            ("hs256_secret", "1234"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.audience(),
+            Some(vec!["a.example.com".into(), "b.example.com".into()])
+        );
+        assert_eq!(config.jwt_leeway_secs, 30);
+        assert_eq!(config.hs256_secret, Some(vec!["1234".into()]));
+        assert_eq!(config.jwks_ttl_secs, 60 * 60);
+        assert!(config.validate().is_ok());
+
+        assert!(self::config(&[("jwt_leeway_secs", "soon")]).is_err());
+        assert!(self::config(&[("max_token_bytes", "abc")]).is_err());
+        assert!(self::config(&[("allow_query_token", "yes")]).is_err());
+    }
+
+    #[test]
+    fn secrets_are_not_debug_printed() {
+        let config = config(&[("hs256_secret", "hunter2"), ("hs256_master", "hunter3")]).unwrap();
+        let debug = format!("{config:?}");
+        assert!(!debug.contains("hunter"), "{debug}");
+        assert!(debug.contains(r#"hs256_secret: Some(["***"])"#), "{debug}");
+    }
+
+    #[test]
+    fn deployment_environments_have_aliases() {
+        assert_eq!(
+            deployment_environment("production"),
+            Some(Environment::Prod)
+        );
+        assert_eq!(deployment_environment("Prod"), Some(Environment::Prod));
+        assert_eq!(deployment_environment("stage"), Some(Environment::Staging));
+        assert_eq!(deployment_environment("test"), Some(Environment::Test));
+        assert_eq!(deployment_environment("development"), None);
+    }
+
+    #[test]
+    fn exclusive_secret_sources_are_refused() {
+        let config = config(&[
+            ("jwt_aud", "a.example.com"),
+            ("hs256_secret", "secret"),
+            ("hs256_master", "master"),
+            ("hs256_info", "outerspace"),
+        ])
+        .unwrap();
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("cannot be set with"), "{err}");
+
+        let config = self::config(&[
+            ("jwt_aud", "a.example.com"),
+            ("hs256_secret", "secret"),
+            ("hs256_secret_file", "/run/secrets/jwt"),
+        ])
+        .unwrap();
+        assert!(config.validate().is_ok());
+    }
+}
@@ -0,0 +1,86 @@
+use jsonwebtoken::Header;
+use rocket::{State, serde::json::Json};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::model::read::AdminUser;
+
+use super::{Checks, Decoders, error::AuthError};
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenStatus {
+    Valid,
+    Expired,
+    BadSignature,
+    /// Malformed, or refused for any other reason than its expiry or signature.
+    Invalid,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenDebug {
+    pub status: TokenStatus,
+    /// Why the token is refused, unless it is valid.
+    pub error: Option<String>,
+    pub header: Option<Header>,
+    /// The claims, only when the signature could be verified.
+    pub claims: Option<Value>,
+}
+
+impl TokenDebug {
+    fn invalid(status: TokenStatus, header: Option<Header>, err: AuthError) -> Self {
+        TokenDebug {
+            status,
+            error: Some(err.to_string()),
+            header,
+            claims: None,
+        }
+    }
+}
+
+/// Decodes the token in the body for support engineers, expired tokens included.
+///
+/// The claims reveal the internals of the token, so this is for admins only.
+#[post("/auth/debug-token", data = "<token>")]
+pub async fn debug_token(
+    _admin: AdminUser,
+    decoders: &State<Decoders>,
+    token: &str,
+) -> Json<TokenDebug> {
+    let token = token.trim();
+    let header = match jsonwebtoken::decode_header(token) {
+        Ok(header) => header,
+        Err(err) => return Json(TokenDebug::invalid(TokenStatus::Invalid, None, err.into())),
+    };
+    let relaxed = Checks {
+        ignore_expiry: true,
+        ..Checks::default()
+    };
+    let claims = match decoders.decode_token::<Value>(token, relaxed).await {
+        Ok(decoded) => decoded.claims,
+        Err(err @ AuthError::InvalidSignature) => {
+            return Json(TokenDebug::invalid(
+                TokenStatus::BadSignature,
+                Some(header),
+                err,
+            ));
+        }
+        Err(err) => {
+            return Json(TokenDebug::invalid(TokenStatus::Invalid, Some(header), err));
+        }
+    };
+    let (status, error) = match decoders
+        .decode_token::<Value>(token, Checks::default())
+        .await
+    {
+        Ok(_) => (TokenStatus::Valid, None),
+        Err(err @ AuthError::Expired) => (TokenStatus::Expired, Some(err.to_string())),
+        Err(err) => (TokenStatus::Invalid, Some(err.to_string())),
+    };
+    Json(TokenDebug {
+        status,
+        error,
+        header: Some(header),
+        claims: Some(claims),
+    })
+}
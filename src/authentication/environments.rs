@@ -0,0 +1,74 @@
+use std::marker::PhantomData;
+
+use rocket::{
+    Request,
+    request::{FromRequest, Outcome},
+};
+
+use crate::authentication::{AccessToken, error::AuthError};
+use crate::model::read::{Environment, Forbidden};
+
+impl From<Forbidden> for AuthError {
+    fn from(value: Forbidden) -> Self {
+        AuthError::Forbidden(value.to_string())
+    }
+}
+
+#[allow(unused)]
+impl AccessToken {
+    /// Requires the token to have been issued for `env`.
+    pub fn require_environment(&self, env: Environment) -> Result<&Self, Forbidden> {
+        if self.requested_environments.contains(&env) {
+            Ok(self)
+        } else {
+            Err(Forbidden)
+        }
+    }
+}
+
+#[allow(unused)]
+pub trait RequiredEnvironment {
+    const ENVIRONMENT: Environment;
+}
+
+macro_rules! environment_markers {
+    ($($marker:ident),* $(,)?) => {
+        $(
+            #[doc = concat!("Marker for [`Environment::", stringify!($marker), "`].")]
+            #[allow(unused)]
+            pub struct $marker;
+
+            impl RequiredEnvironment for $marker {
+                const ENVIRONMENT: Environment = Environment::$marker;
+            }
+        )*
+    };
+}
+
+environment_markers!(Prod, Staging, Test);
+
+/// The access token of a request that was issued for the environment `E`.
+#[allow(unused)]
+pub struct EnvUser<E: RequiredEnvironment> {
+    pub token: AccessToken,
+    environment: PhantomData<E>,
+}
+
+#[async_trait]
+impl<'r, E: RequiredEnvironment> FromRequest<'r> for EnvUser<E> {
+    type Error = AuthError;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = match request.guard::<AccessToken>().await {
+            Outcome::Success(token) => token,
+            Outcome::Error(err) => return Outcome::Error(err),
+            Outcome::Forward(x) => return Outcome::Forward(x),
+        };
+        match token.require_environment(E::ENVIRONMENT) {
+            Ok(_) => Outcome::Success(EnvUser {
+                token,
+                environment: PhantomData,
+            }),
+            Err(err) => AuthError::from(err).outcome(request),
+        }
+    }
+}
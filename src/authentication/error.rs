@@ -0,0 +1,221 @@
+use jsonwebtoken::errors::ErrorKind;
+use rocket::{
+    Request,
+    http::{Header, MediaType, Status},
+    request::Outcome,
+    response::{self, Responder},
+    serde::json::Json,
+};
+use serde::Serialize;
+use thiserror::Error;
+
+/// Why a request could not be authenticated or authorized.
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum AuthError {
+    #[error("missing authorization token")]
+    MissingToken,
+    #[error("token has expired")]
+    Expired,
+    #[error("invalid token signature")]
+    InvalidSignature,
+    #[error("unknown token key")]
+    UnknownKey,
+    #[error("token has been revoked")]
+    Revoked,
+    #[error("user has been deleted")]
+    UserDeleted,
+    #[allow(unused)]
+    #[error("authentication is older than {max_age} seconds, sign in again")]
+    StaleAuthentication { max_age: u64 },
+    #[error("invalid token: {0}")]
+    InvalidToken(String),
+    #[error("{0}")]
+    InsufficientScope(String),
+    #[error("user not found")]
+    UserNotFound,
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    Internal(String),
+    #[error("no jwt decoding keys are loaded")]
+    Unavailable,
+    #[error("too many invalid tokens, try again later")]
+    RateLimited,
+}
+
+/// The error a guard failed with, read back by the catchers.
+#[derive(Default)]
+struct RecordedAuthError(Option<AuthError>);
+
+impl AuthError {
+    pub fn status(&self) -> Status {
+        match self {
+            AuthError::InsufficientScope(_) | AuthError::Forbidden(_) => Status::Forbidden,
+            AuthError::UserNotFound => Status::NotFound,
+            AuthError::Internal(_) => Status::InternalServerError,
+            AuthError::Unavailable => Status::ServiceUnavailable,
+            AuthError::RateLimited => Status::TooManyRequests,
+            _ => Status::Unauthorized,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::MissingToken => "missing_token",
+            AuthError::Expired => "token_expired",
+            AuthError::InvalidSignature => "invalid_signature",
+            AuthError::UnknownKey => "unknown_key",
+            AuthError::Revoked => "token_revoked",
+            AuthError::UserDeleted => "user_deleted",
+            AuthError::StaleAuthentication { .. } => "insufficient_user_authentication",
+            AuthError::InvalidToken(_) => "invalid_token",
+            AuthError::InsufficientScope(_) => "insufficient_scope",
+            AuthError::UserNotFound => "user_not_found",
+            AuthError::Forbidden(_) => "forbidden",
+            AuthError::Internal(_) => "internal_error",
+            AuthError::Unavailable => "service_unavailable",
+            AuthError::RateLimited => "too_many_requests",
+        }
+    }
+
+    /// The coarser category of the error, used as the label of the failure metrics.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            AuthError::MissingToken => "missing",
+            AuthError::Expired => "expired",
+            AuthError::UnknownKey => "unknown_key",
+            AuthError::Revoked => "revoked",
+            AuthError::UserDeleted => "user_deleted",
+            AuthError::StaleAuthentication { .. } => "stale_authentication",
+            AuthError::InvalidSignature | AuthError::InvalidToken(_) => "invalid",
+            AuthError::InsufficientScope(_) => "insufficient_scope",
+            AuthError::UserNotFound => "user_not_found",
+            AuthError::Forbidden(_) => "forbidden",
+            AuthError::Internal(_) => "internal",
+            AuthError::Unavailable => "unavailable",
+            AuthError::RateLimited => "rate_limited",
+        }
+    }
+
+    /// Fails a request guard with this error, remembering it for the catchers.
+    pub fn outcome<T>(self, request: &Request<'_>) -> Outcome<T, AuthError> {
+        request.local_cache(|| RecordedAuthError(Some(self.clone())));
+        Outcome::Error((self.status(), self))
+    }
+
+    /// The `WWW-Authenticate` challenge required by RFC 6750 for 401s, and the step up challenge
+    /// of RFC 9470 for stale authentications.
+    fn challenge(&self) -> Option<Header<'static>> {
+        let value = match self {
+            AuthError::MissingToken => "Bearer".to_string(),
+            AuthError::StaleAuthentication { max_age } => format!(
+                r#"Bearer error="insufficient_user_authentication", error_description="{self}", max_age={max_age}"#
+            ),
+            _ if self.status() == Status::Unauthorized => format!(
+                r#"Bearer error="invalid_token", error_description="{}""#,
+                self.to_string().replace('"', "'")
+            ),
+            _ => return None,
+        };
+        Some(Header::new("WWW-Authenticate", value))
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for AuthError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        match err.kind() {
+            ErrorKind::ExpiredSignature => AuthError::Expired,
+            ErrorKind::InvalidSignature => AuthError::InvalidSignature,
+            _ => AuthError::InvalidToken(err.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub error: &'static str,
+    pub message: String,
+}
+
+/// Whether the client prefers a plain text error over the default JSON one, like CLI clients do.
+fn prefers_plain_text(request: &Request<'_>) -> bool {
+    request
+        .accept()
+        .is_some_and(|accept| accept.preferred().media_type() == &MediaType::Plain)
+}
+
+/// An error response, as JSON `{error, message}` or only the message as plain text when the
+/// `Accept` header prefers it.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Status,
+    pub error: &'static str,
+    pub message: String,
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        if prefers_plain_text(request) {
+            return (self.status, self.message).respond_to(request);
+        }
+        let body = ErrorBody {
+            error: self.error,
+            message: self.message,
+        };
+        (self.status, Json(body)).respond_to(request)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for AuthError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let error = ApiError {
+            status: self.status(),
+            error: self.code(),
+            message: self.to_string(),
+        };
+        let mut response = error.respond_to(request)?;
+        if let Some(challenge) = self.challenge() {
+            response.set_header(challenge);
+        }
+        Ok(response)
+    }
+}
+
+#[catch(401)]
+pub fn unauthorized(request: &Request) -> AuthError {
+    match &request.local_cache(RecordedAuthError::default).0 {
+        Some(err) if err.status() == Status::Unauthorized => err.clone(),
+        _ => AuthError::MissingToken,
+    }
+}
+
+/// Names the missing scope when a guard recorded why.
+#[catch(403)]
+pub fn forbidden(request: &Request) -> AuthError {
+    match &request.local_cache(RecordedAuthError::default).0 {
+        Some(err) if err.status() == Status::Forbidden => err.clone(),
+        _ => AuthError::Forbidden("access to this resource is forbidden".into()),
+    }
+}
+
+/// Also answers for valid tokens whose user has no account, unlike rocket's default catcher.
+#[catch(404)]
+pub fn not_found(request: &Request) -> ApiError {
+    let (error, message) = match &request.local_cache(RecordedAuthError::default).0 {
+        Some(err) if err.status() == Status::NotFound => (err.code(), err.to_string()),
+        _ => (
+            "not_found",
+            format!("nothing found at '{}'", request.uri().path()),
+        ),
+    };
+    ApiError {
+        status: Status::NotFound,
+        error,
+        message,
+    }
+}
+
+#[catch(429)]
+pub fn too_many_requests() -> AuthError {
+    AuthError::RateLimited
+}
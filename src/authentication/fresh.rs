@@ -0,0 +1,74 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use rocket::{
+    Request,
+    request::{FromRequest, Outcome},
+};
+
+use super::{AccessToken, error::AuthError};
+
+/// The access token of a request whose user authenticated at most `MAX_AGE_SECS` ago, for
+/// sensitive actions. Admin routes take it next to [`AdminUser`], for example:
+///
+/// ```ignore
+/// #[delete("/users/<id>")]
+/// pub async fn delete_user(admin: AdminUser, _fresh: FreshAuth<300>, id: Uuid) { ... }
+/// ```
+///
+/// Tokens without `auth_time` are refused, as they cannot tell when the user authenticated.
+///
+/// [`AdminUser`]: crate::model::read::AdminUser
+#[allow(unused)]
+pub struct FreshAuth<const MAX_AGE_SECS: u64> {
+    pub token: AccessToken,
+}
+
+#[async_trait]
+impl<'r, const MAX_AGE_SECS: u64> FromRequest<'r> for FreshAuth<MAX_AGE_SECS> {
+    type Error = AuthError;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = match request.guard::<AccessToken>().await {
+            Outcome::Success(token) => token,
+            Outcome::Error(err) => return Outcome::Error(err),
+            Outcome::Forward(x) => return Outcome::Forward(x),
+        };
+        if is_fresh(token.authenticated_at(), MAX_AGE_SECS, Utc::now()) {
+            Outcome::Success(FreshAuth { token })
+        } else {
+            AuthError::StaleAuthentication {
+                max_age: MAX_AGE_SECS,
+            }
+            .outcome(request)
+        }
+    }
+}
+
+/// Whether the user authenticated at most `max_age_secs` before `now`. An authentication after
+/// `now` is not trusted either.
+#[allow(unused)]
+fn is_fresh(
+    authenticated_at: Option<DateTime<Utc>>,
+    max_age_secs: u64,
+    now: DateTime<Utc>,
+) -> bool {
+    let max_age = i64::try_from(max_age_secs)
+        .ok()
+        .and_then(TimeDelta::try_seconds)
+        .unwrap_or(TimeDelta::MAX);
+    authenticated_at.is_some_and(|at| at <= now && now - at <= max_age)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authentications_in_the_future_are_not_fresh() {
+        let now = Utc::now();
+        let minutes_ago = now - TimeDelta::minutes(2);
+        assert!(is_fresh(Some(minutes_ago), 300, now));
+        assert!(!is_fresh(Some(minutes_ago), 60, now));
+        assert!(!is_fresh(Some(now + TimeDelta::minutes(2)), 300, now));
+        assert!(!is_fresh(None, 300, now));
+        assert!(is_fresh(Some(DateTime::UNIX_EPOCH), u64::MAX, now));
+    }
+}
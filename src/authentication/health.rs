@@ -0,0 +1,78 @@
+use std::{convert::Infallible, time::Duration};
+
+use jsonwebtoken::jwk::JwkSet;
+use rocket::{
+    Request, State,
+    http::Status,
+    request::{FromRequest, Outcome},
+    serde::json::Json,
+};
+use serde::Serialize;
+use tracing::warn;
+
+use super::{Decoders, config::AuthConfig};
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    /// Whether any jwt decoding keys were loaded at ignite.
+    pub decoders: bool,
+    /// How many keys were loaded from the JWKS, if that is where they came from.
+    pub jwks_keys: Option<usize>,
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for Health {
+    type Error = Infallible;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let decoders = request.rocket().state::<Decoders>();
+        let jwks_keys = match decoders {
+            Some(Decoders::Multiple(cache) | Decoders::Combined { cache, .. }) => {
+                Some(cache.key_count().await)
+            }
+            _ => None,
+        };
+        Outcome::Success(Health {
+            decoders: decoders.is_some(),
+            jwks_keys,
+        })
+    }
+}
+
+#[get("/health")]
+pub fn get_health(health: Health) -> Json<Health> {
+    Json(health)
+}
+
+async fn probe_jwks(url: &str, timeout: Duration) -> anyhow::Result<JwkSet> {
+    let client = reqwest::Client::builder().timeout(timeout).build()?;
+    let response = client.get(url).send().await?.error_for_status()?;
+    Ok(response.json::<JwkSet>().await?)
+}
+
+/// Whether tokens can be validated: some decoding keys are loaded, and a key set that is the
+/// only source of keys has at least one key.
+async fn keys_loaded(decoders: Option<&State<Decoders>>) -> bool {
+    match decoders.map(|decoders| decoders.inner()) {
+        None => false,
+        Some(Decoders::Multiple(cache)) => cache.key_count().await > 0,
+        Some(_) => true,
+    }
+}
+
+/// Ready once the decoding keys are loaded and every JWKS can be fetched, so no request has to
+/// wait for the first fetch of the key set.
+#[get("/ready")]
+pub async fn get_ready(decoders: Option<&State<Decoders>>, config: &State<AuthConfig>) -> Status {
+    if !keys_loaded(decoders).await {
+        warn!("No jwt decoding keys are loaded yet");
+        return Status::ServiceUnavailable;
+    }
+    let timeout = Duration::from_millis(config.jwks_probe_timeout_ms);
+    for url in config.jwks_url.iter().flatten() {
+        if let Err(err) = probe_jwks(url, timeout).await {
+            warn!(url, error = %err, "JWKS is not reachable");
+            return Status::ServiceUnavailable;
+        }
+    }
+    Status::Ok
+}
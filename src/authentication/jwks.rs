@@ -0,0 +1,529 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    fs, slice,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{
+    Algorithm,
+    jwk::{AlgorithmParameters, Jwk, JwkSet},
+};
+use metrics::counter;
+use rocket::{
+    Request, State,
+    http::{Header, Status},
+    request::{FromRequest, Outcome},
+    serde::json::Json,
+    tokio::sync::{Mutex, RwLock},
+};
+use serde::{Serialize, de::DeserializeOwned};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::model::read::AdminUser;
+
+use super::{
+    Checks, Decoder, Decoders, config::AuthConfig, decode_any, error::AuthError, fetch_jwk_set,
+    fetch_jwk_set_with_retries, jwk_decoders,
+};
+
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The decoders built from the JWKS, refetched when a token uses an unknown key.
+pub struct JwksCache {
+    source: JwksSource,
+    keys: RwLock<CachedKeys>,
+    /// When the last refresh was attempted, also serializes refreshes.
+    last_refresh: Mutex<Option<Instant>>,
+    ttl: Duration,
+}
+
+struct CachedKeys {
+    decoders: HashMap<String, Vec<Decoder>>,
+    /// The key id of each key by its RFC 7638 thumbprint, for tokens that use it as their `kid`.
+    thumbprints: HashMap<String, String>,
+    /// The public keys of the set, symmetric keys are never kept here.
+    public: JwkSet,
+    /// The entity tag of the public keys, which only changes with them.
+    etag: String,
+    fetched_at: Instant,
+}
+
+impl CachedKeys {
+    fn new(config: &AuthConfig, key_set: JwkSet) -> Self {
+        let decoders = jwk_decoders(config, &key_set);
+        let thumbprints = key_set
+            .keys
+            .iter()
+            .filter_map(|jwk| Some((thumbprint(jwk)?, jwk.common.key_id.clone()?)))
+            .collect();
+        let public = public_keys(key_set);
+        Self {
+            decoders,
+            thumbprints,
+            etag: etag(&public),
+            public,
+            fetched_at: Instant::now(),
+        }
+    }
+
+    /// The decoders of the key with the id `kid`, or else of the key with that thumbprint.
+    fn find(&self, kid: &str) -> Option<&[Decoder]> {
+        self.decoders
+            .get(kid)
+            .or_else(|| self.decoders.get(self.thumbprints.get(kid)?))
+            .map(Vec::as_slice)
+    }
+
+    /// Adds the keys of `other`, which wins when both have a key with the same id.
+    fn merge(&mut self, other: CachedKeys) {
+        self.decoders.extend(other.decoders);
+        self.thumbprints.extend(other.thumbprints);
+        self.public.keys.retain(|key| {
+            other
+                .public
+                .keys
+                .iter()
+                .all(|new| new.common.key_id != key.common.key_id)
+        });
+        self.public.keys.extend(other.public.keys);
+        self.etag = etag(&self.public);
+        self.fetched_at = other.fetched_at;
+    }
+}
+
+fn public_keys(mut key_set: JwkSet) -> JwkSet {
+    key_set
+        .keys
+        .retain(|jwk| !matches!(jwk.algorithm, AlgorithmParameters::OctetKey(_)));
+    key_set
+}
+
+/// The RFC 7638 thumbprint of a public key: the base64url encoded SHA-256 hash of its required
+/// members, in lexicographic order and without whitespace.
+pub(super) fn thumbprint(jwk: &Jwk) -> Option<String> {
+    let members = match &jwk.algorithm {
+        AlgorithmParameters::RSA(rsa) => {
+            format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, rsa.e, rsa.n)
+        }
+        AlgorithmParameters::EllipticCurve(ec) => format!(
+            r#"{{"crv":{},"kty":"EC","x":"{}","y":"{}"}}"#,
+            serde_json::to_string(&ec.curve).ok()?,
+            ec.x,
+            ec.y
+        ),
+        AlgorithmParameters::OctetKeyPair(okp) => format!(
+            r#"{{"crv":{},"kty":"OKP","x":"{}"}}"#,
+            serde_json::to_string(&okp.curve).ok()?,
+            okp.x
+        ),
+        AlgorithmParameters::OctetKey(_) => return None,
+    };
+    Some(URL_SAFE_NO_PAD.encode(Sha256::digest(members)))
+}
+
+/// A SHA-256 of the serialized keys sorted by `kid`, the same for the same keys in every
+/// instance and every build.
+fn etag(key_set: &JwkSet) -> String {
+    let mut keys: Vec<&Jwk> = key_set.keys.iter().collect();
+    keys.sort_by(|a, b| a.common.key_id.cmp(&b.common.key_id));
+    let serialized = serde_json::to_vec(&keys).unwrap_or_default();
+    format!("\"{}\"", URL_SAFE_NO_PAD.encode(Sha256::digest(serialized)))
+}
+
+/// Where the key set comes from: fetched from the comma separated urls in `AUTH_JWKS_URL`, or
+/// read from the file at `AUTH_JWKS_FILE` when there is no url or none can be fetched.
+struct JwksSource {
+    urls: Vec<String>,
+    file: Option<String>,
+    config: AuthConfig,
+}
+
+impl JwksSource {
+    fn new(config: &AuthConfig) -> anyhow::Result<Self> {
+        let urls = config.jwks_url.clone().unwrap_or_default();
+        let file = config.jwks_file.clone();
+        if urls.is_empty() && file.is_none() {
+            anyhow::bail!("neither AUTH_JWKS_URL nor AUTH_JWKS_FILE is set");
+        }
+        Ok(Self {
+            urls,
+            file,
+            config: config.clone(),
+        })
+    }
+
+    async fn fetch(&self, retry: bool) -> anyhow::Result<JwkSet> {
+        if !self.urls.is_empty() {
+            match (self.fetch_urls(retry).await, &self.file) {
+                (Ok(key_set), _) => return Ok(key_set),
+                (Err(err), Some(file)) => {
+                    warn!(file, error = %err, "Failed to fetch jwk key set, reading the file")
+                }
+                (Err(err), None) => return Err(err),
+            }
+        }
+        let file = self.file.as_deref().context("AUTH_JWKS_FILE is not set")?;
+        let json = fs::read_to_string(file)
+            .with_context(|| format!("could not read AUTH_JWKS_FILE '{file}'"))?;
+        serde_json::from_str(&json).with_context(|| format!("invalid jwk key set in '{file}'"))
+    }
+
+    /// Fetches the key set of every url and merges them, keeping the key of the first url
+    /// when several use the same key id. Fails only when none of the urls can be fetched.
+    async fn fetch_urls(&self, retry: bool) -> anyhow::Result<JwkSet> {
+        let mut merged = JwkSet { keys: Vec::new() };
+        let mut last_err = None;
+        for url in &self.urls {
+            let fetched = if retry {
+                fetch_jwk_set_with_retries(&self.config, url).await
+            } else {
+                fetch_jwk_set(&self.config, url).await
+            };
+            let key_set = match fetched {
+                Ok(key_set) => key_set,
+                Err(err) => {
+                    warn!(url, error = %err, "Failed to fetch jwk key set");
+                    last_err = Some(err);
+                    continue;
+                }
+            };
+            let mut collisions = Vec::new();
+            let known: HashSet<String> = merged
+                .keys
+                .iter()
+                .filter_map(|jwk| jwk.common.key_id.clone())
+                .collect();
+            for jwk in key_set.keys {
+                match &jwk.common.key_id {
+                    Some(kid) if known.contains(kid) => collisions.push(kid.clone()),
+                    _ => merged.keys.push(jwk),
+                }
+            }
+            if !collisions.is_empty() {
+                warn!(url, kids = ?collisions, "Ignoring keys whose id an earlier jwk key set uses");
+            }
+        }
+        match last_err {
+            Some(err) if merged.keys.is_empty() => Err(err),
+            _ => Ok(merged),
+        }
+    }
+}
+
+impl JwksCache {
+    pub async fn load(config: &AuthConfig) -> anyhow::Result<Self> {
+        let source = JwksSource::new(config)?;
+        let key_set = source.fetch(true).await?;
+        Ok(Self {
+            keys: RwLock::new(CachedKeys::new(config, key_set)),
+            source,
+            last_refresh: Mutex::new(None),
+            ttl: config.jwks_ttl(),
+        })
+    }
+
+    /// The ids and algorithms of the cached keys, ordered by id.
+    pub async fn loaded_keys(&self) -> Vec<LoadedKey> {
+        let keys = self.keys.read().await;
+        let mut loaded: Vec<LoadedKey> = keys
+            .decoders
+            .iter()
+            .flat_map(|(kid, decoders)| {
+                decoders.iter().map(|decoder| LoadedKey {
+                    kid: kid.clone(),
+                    alg: decoder.algorithm(),
+                })
+            })
+            .collect();
+        loaded.sort_by(|a, b| a.kid.cmp(&b.kid));
+        loaded
+    }
+
+    pub async fn key_count(&self) -> usize {
+        self.keys.read().await.decoders.values().map(Vec::len).sum()
+    }
+
+    pub async fn decode<T: DeserializeOwned>(
+        &self,
+        kid: &str,
+        token: &str,
+        checks: Checks<'_>,
+    ) -> Result<T, AuthError> {
+        if let Some(result) = self.try_decode(kid, token, checks).await {
+            return result;
+        }
+        if let Err(err) = self.refresh().await {
+            warn!(kid, error = %err, "Failed to refresh jwk key set");
+        }
+        self.try_decode(kid, token, checks)
+            .await
+            .unwrap_or(Err(AuthError::UnknownKey))
+    }
+
+    async fn try_decode<T: DeserializeOwned>(
+        &self,
+        kid: &str,
+        token: &str,
+        checks: Checks<'_>,
+    ) -> Option<Result<T, AuthError>> {
+        let keys = self.keys.read().await;
+        keys.find(kid)
+            .map(|decoders| decode_any(decoders, token, checks))
+    }
+
+    /// Fetches the key set again, at most once per [`MIN_REFRESH_INTERVAL`].
+    ///
+    /// New keys are merged into the cached ones, unless the cache is older than the ttl,
+    /// in which case it is replaced so that retired keys are dropped. The lock on the keys
+    /// is not held while fetching, so requests with known keys are never blocked.
+    async fn refresh(&self) -> anyhow::Result<()> {
+        let mut last_refresh = self.last_refresh.lock().await;
+        if last_refresh.is_some_and(|at| at.elapsed() < MIN_REFRESH_INTERVAL) {
+            return Ok(());
+        }
+        *last_refresh = Some(Instant::now());
+        counter!("jwks_refresh_total").increment(1);
+        let fetched = CachedKeys::new(&self.source.config, self.source.fetch(false).await?);
+
+        let mut keys = self.keys.write().await;
+        if keys.fetched_at.elapsed() >= self.ttl {
+            *keys = fetched;
+        } else {
+            keys.merge(fetched);
+        }
+        Ok(())
+    }
+
+    /// Fetches the key set now and replaces the cached keys with it, returning how many keys
+    /// there are. The cached keys are kept when the fetch fails or has no usable keys.
+    pub async fn force_refresh(&self) -> anyhow::Result<usize> {
+        let mut last_refresh = self.last_refresh.lock().await;
+        *last_refresh = Some(Instant::now());
+        counter!("jwks_refresh_total").increment(1);
+        let fetched = CachedKeys::new(&self.source.config, self.source.fetch(false).await?);
+        let count = fetched.decoders.values().map(Vec::len).sum();
+        if count == 0 {
+            anyhow::bail!("the fetched jwk key set has no usable keys");
+        }
+        *self.keys.write().await = fetched;
+        Ok(count)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoadedKey {
+    pub kid: String,
+    pub alg: Algorithm,
+}
+
+/// Which keys tokens are validated with, without any of the key material.
+#[derive(Debug, Serialize)]
+pub struct LoadedKeys {
+    pub jwks: Vec<LoadedKey>,
+    pub hs256_secrets: usize,
+    pub ed25519_keys: usize,
+    pub rs256_keys: usize,
+}
+
+#[get("/auth/keys")]
+pub async fn get_loaded_keys(_admin: AdminUser, decoders: &State<Decoders>) -> Json<LoadedKeys> {
+    let (jwks, static_keys): (_, &[Decoder]) = match decoders.inner() {
+        Decoders::Single(decoder) => (Vec::new(), slice::from_ref(&**decoder)),
+        Decoders::Rotating(static_keys) => (Vec::new(), static_keys),
+        Decoders::Multiple(cache) => (cache.loaded_keys().await, &[]),
+        Decoders::Combined { cache, static_keys } => (cache.loaded_keys().await, static_keys),
+    };
+    let count = |alg| {
+        static_keys
+            .iter()
+            .filter(|decoder| decoder.algorithm() == alg)
+            .count()
+    };
+    Json(LoadedKeys {
+        jwks,
+        hs256_secrets: count(Algorithm::HS256),
+        ed25519_keys: count(Algorithm::EdDSA),
+        rs256_keys: count(Algorithm::RS256),
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct RefreshedKeys {
+    pub keys: usize,
+}
+
+/// Refetches the key set right away, for when the identity provider is known to have rotated
+/// its keys. Answers 502 when it cannot be fetched or is empty, still validating with the old
+/// keys.
+#[post("/auth/keys/refresh")]
+pub async fn refresh_keys(
+    _admin: AdminUser,
+    decoders: &State<Decoders>,
+) -> Result<Json<RefreshedKeys>, Status> {
+    let (Decoders::Multiple(cache) | Decoders::Combined { cache, .. }) = decoders.inner() else {
+        return Err(Status::NotFound);
+    };
+    match cache.force_refresh().await {
+        Ok(keys) => Ok(Json(RefreshedKeys { keys })),
+        Err(err) => {
+            warn!(error = %err, "Failed to refresh jwk key set");
+            Err(Status::BadGateway)
+        }
+    }
+}
+
+/// The entity tags of the `If-None-Match` header, if any.
+pub struct IfNoneMatch(Option<String>);
+
+impl IfNoneMatch {
+    fn matches(&self, etag: &str) -> bool {
+        self.0.as_deref().is_some_and(|tags| {
+            tags.split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == "*" || tag == etag)
+        })
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatch {
+    type Error = Infallible;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let tags = request.headers().get_one("If-None-Match").map(String::from);
+        Outcome::Success(IfNoneMatch(tags))
+    }
+}
+
+#[derive(Responder)]
+pub enum PublishedKeys {
+    Keys {
+        keys: Json<JwkSet>,
+        etag: Header<'static>,
+        cache_control: Header<'static>,
+    },
+    #[response(status = 304)]
+    NotModified {
+        body: (),
+        etag: Header<'static>,
+        cache_control: Header<'static>,
+    },
+}
+
+/// The public keys this service trusts, so other services need not ask the identity provider.
+///
+/// Deployments with only a symmetric secret publish an empty set. Answers 304 when the client
+/// already has the keys, going by their `ETag`.
+#[get("/.well-known/jwks.json")]
+pub async fn get_jwks(
+    decoders: &State<Decoders>,
+    config: &State<AuthConfig>,
+    if_none_match: IfNoneMatch,
+) -> PublishedKeys {
+    let (keys, tag, ttl) = match decoders.inner() {
+        Decoders::Multiple(cache) | Decoders::Combined { cache, .. } => {
+            let cached = cache.keys.read().await;
+            (cached.public.clone(), cached.etag.clone(), cache.ttl)
+        }
+        Decoders::Single(_) | Decoders::Rotating(_) => {
+            let empty = JwkSet { keys: Vec::new() };
+            let tag = etag(&empty);
+            (empty, tag, config.jwks_ttl())
+        }
+    };
+    let cache_control = Header::new(
+        "Cache-Control",
+        format!("public, max-age={}", ttl.as_secs()),
+    );
+    if if_none_match.matches(&tag) {
+        return PublishedKeys::NotModified {
+            body: (),
+            etag: Header::new("ETag", tag),
+            cache_control,
+        };
+    }
+    PublishedKeys::Keys {
+        keys: Json(keys),
+        etag: Header::new("ETag", tag),
+        cache_control,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example key of RFC 7638, section 3.1.
+    fn rfc_key() -> JwkSet {
+        serde_json::from_value(serde_json::json!({
+            "keys": [{
+                "kty": "RSA",
+                "kid": "2011-04-29",
+                "alg": "RS256",
+                "e": "AQAB",
+                "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+            }]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn keys_are_found_by_their_thumbprint() {
+        let keys = CachedKeys::new(&AuthConfig::default(), rfc_key());
+        let expected = "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs";
+
+        assert_eq!(thumbprint(&keys.public.keys[0]).as_deref(), Some(expected));
+        assert!(keys.find("2011-04-29").is_some());
+        assert!(keys.find(expected).is_some());
+        assert!(keys.find("unknown").is_none());
+    }
+
+    #[test]
+    fn keys_without_an_id_are_found_by_their_thumbprint() {
+        let mut key_set = rfc_key();
+        key_set.keys[0].common.key_id = None;
+        let keys = CachedKeys::new(&AuthConfig::default(), key_set);
+
+        assert!(
+            keys.find("NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs")
+                .is_some()
+        );
+        assert!(keys.find("2011-04-29").is_none());
+    }
+
+    #[test]
+    fn etag_does_not_depend_on_the_order_of_the_keys() {
+        let mut key_set = rfc_key();
+        let mut other = key_set.keys[0].clone();
+        other.common.key_id = Some("2011-04-30".into());
+        key_set.keys.push(other);
+        let tag = etag(&key_set);
+
+        key_set.keys.reverse();
+        assert_eq!(etag(&key_set), tag);
+        key_set.keys.pop();
+        assert_ne!(etag(&key_set), tag);
+    }
+
+    #[rocket::async_test]
+    async fn an_empty_key_set_does_not_replace_the_cached_keys() {
+        let file = std::env::temp_dir().join(format!("jwks-{}.json", std::process::id()));
+        fs::write(&file, serde_json::to_string(&rfc_key()).unwrap()).unwrap();
+        let config = AuthConfig {
+            jwks_file: Some(file.display().to_string()),
+            ..AuthConfig::default()
+        };
+        let cache = JwksCache::load(&config).await.unwrap();
+
+        fs::write(&file, r#"{"keys": []}"#).unwrap();
+        let refreshed = cache.force_refresh().await;
+        fs::remove_file(&file).unwrap();
+
+        assert!(refreshed.is_err());
+        assert_eq!(cache.key_count().await, 1);
+    }
+}
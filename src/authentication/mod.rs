@@ -1,145 +1,944 @@
 use std::{
-    collections::HashMap,
-    env::{self, VarError},
+    collections::{HashMap, HashSet},
+    fs,
+    net::IpAddr,
+    time::{Duration, Instant},
 };
 
-use anyhow::anyhow;
-use jsonwebtoken::{Algorithm, DecodingKey, Validation, jwk::JwkSet};
+use anyhow::Context;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use email_address::EmailAddress;
+use hkdf::Hkdf;
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Header, Validation,
+    errors::{Error as JwtError, ErrorKind},
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet},
+};
+use metrics::counter;
 use rocket::{
     Request, State,
     fairing::{AdHoc, Fairing},
-    http::Status,
-    log::private::warn,
+    http::{Header as HttpHeader, Status},
     request::{FromRequest, Outcome},
     serde::json::Json,
+    tokio::time::sleep,
 };
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
+use sha2::Sha256;
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-use permissions::Permission;
+use config::AuthConfig;
+use error::AuthError;
+use jwks::JwksCache;
+use permissions::Scopes;
+use ratelimit::FailedAttempts;
+use revocation::RevokedTokens;
+use roles::RoleMap;
 
-use crate::model::read::{AdminUser, AuthorizedUser};
+use crate::{
+    model::read::{AdminUser, AuthorizedUser, Environment, parse_email, platform::User},
+    repository::Users,
+    request_id::RequestId,
+};
 
+pub mod audience;
+pub mod config;
+pub mod debug;
+pub mod environments;
+pub mod error;
+pub mod fresh;
+pub mod health;
+pub mod jwks;
 pub mod permissions;
+pub mod public;
+pub mod ratelimit;
+pub mod revocation;
+pub mod roles;
+mod selftest;
+pub mod verify;
+
+/// Reports at launch when no decoding keys are managed, in which case every token is refused.
+pub fn check_keys() -> impl Fairing {
+    AdHoc::on_liftoff("Check jwt decoding keys", |rocket| {
+        Box::pin(async move {
+            if rocket.state::<Decoders>().is_none() {
+                error!("no jwt decoding key found, authenticated routes will respond with 503");
+            }
+        })
+    })
+}
+
+/// How long decoding the token of the request took.
+#[derive(Default)]
+struct DecodeTime(Option<Duration>);
+
+/// Adds the `X-Auth-Time-Micros` header with how long decoding the token took, when
+/// `AUTH_DEBUG_TIMING=true`, to tell signature verification apart from the user lookup.
+pub fn timing() -> impl Fairing {
+    AdHoc::on_response("Auth timing header", |request, response| {
+        Box::pin(async move {
+            let config = request.rocket().state::<AuthConfig>();
+            if !config.is_some_and(|config| config.debug_timing) {
+                return;
+            }
+            if let DecodeTime(Some(elapsed)) = request.local_cache(DecodeTime::default) {
+                response.set_header(HttpHeader::new(
+                    "X-Auth-Time-Micros",
+                    elapsed.as_micros().to_string(),
+                ));
+            }
+        })
+    })
+}
 
+/// Reads the [`AuthConfig`] and loads the decoding keys, unless some were already given to the
+/// rocket.
 pub fn fairing() -> impl Fairing {
     AdHoc::try_on_ignite("Load jwt decoding keys", |rocket| async {
-        let keys = match (fetch_jwk_set().await, load_jwk_secret()) {
-            (Ok(map), _) => Decoders::Multiple(map),
-            (Err(_), Ok(decoder)) => {
-                warn!("using single jwt key secret");
-                Decoders::Single(decoder.into())
-            }
-            (Err(multiple_err), Err(single_err)) => {
-                error!("Failed to fetch jwk key set: {multiple_err}");
-                error!("Failed to fetch jwk secret: {single_err}");
+        let config = match AuthConfig::from_env() {
+            Ok(config) => config,
+            Err(err) => {
+                error!(
+                    error = format!("{err:#}"),
+                    "Failed to read the auth configuration"
+                );
                 return Err(rocket);
             }
         };
+        let roles = match RoleMap::load(&config) {
+            Ok(roles) => roles,
+            Err(err) => {
+                error!(error = %err, "Failed to load the role map");
+                return Err(rocket);
+            }
+        };
+        let mut rocket = rocket
+            .manage(RevokedTokens::default())
+            .manage(roles)
+            .manage(config.clone());
+        if config.ratelimit_max_failures > 0 && rocket.state::<FailedAttempts>().is_none() {
+            rocket = rocket.manage(FailedAttempts::new(
+                config.ratelimit_window(),
+                config.ratelimit_max_failures,
+            ));
+        }
+        if rocket.state::<Decoders>().is_some() {
+            return Ok(rocket);
+        }
+        if let Err(err) = config.validate() {
+            error!(error = %err, "Invalid auth configuration");
+            return Err(rocket);
+        }
+        if let Some(environment) = config.environment {
+            info!(%environment, "using the jwt audience of the environment");
+        }
+        if !config.jwt_validate_aud {
+            warn!("jwt audience validation is disabled, never do this in production");
+        }
+        let secrets = match load_jwk_secrets(&config) {
+            Ok(secrets) => secrets.unwrap_or_default(),
+            Err(err) => {
+                error!(
+                    error = format!("{err:#}"),
+                    "Failed to load the jwt key secrets"
+                );
+                return Err(rocket);
+            }
+        };
+        let public_keys = [
+            (
+                "ed25519",
+                &config.ed25519_public_key,
+                &config.ed25519_public_key_file,
+                Decoder::ed25519 as fn(&AuthConfig, &[u8]) -> _,
+            ),
+            (
+                "rs256",
+                &config.rs256_public_key,
+                &config.rs256_public_key_file,
+                Decoder::rs256,
+            ),
+        ];
+        let mut static_keys: Vec<Decoder> = secrets
+            .iter()
+            .map(|secret| Decoder::hs256(&config, secret))
+            .collect();
+        for (name, pem, file, decoder) in public_keys {
+            let key = load_public_key(pem, file)
+                .and_then(|pem| pem.map(|pem| decoder(&config, pem.as_bytes())).transpose());
+            match key {
+                Ok(key) => static_keys.extend(key),
+                Err(err) => {
+                    error!(
+                        error = format!("{err:#}"),
+                        "Failed to load the {name} public key"
+                    );
+                    return Err(rocket);
+                }
+            }
+        }
+        let keys = match (JwksCache::load(&config).await, static_keys.len()) {
+            (Ok(cache), 0) => Decoders::Multiple(cache.into()),
+            (Ok(cache), _) => {
+                info!(
+                    static_keys = static_keys.len(),
+                    "using jwk key set and static jwt keys"
+                );
+                Decoders::Combined {
+                    cache: cache.into(),
+                    static_keys,
+                }
+            }
+            (Err(multiple_err), 0) => {
+                error!(error = %multiple_err, "Failed to fetch jwk key set");
+                error!("no static jwt key is configured either");
+                return Err(rocket);
+            }
+            (Err(_), 1) => {
+                warn!(alg = ?static_keys[0].algorithm(), "using single static jwt key");
+                Decoders::Single(static_keys.remove(0).into())
+            }
+            (Err(_), _) => {
+                warn!(
+                    static_keys = static_keys.len(),
+                    "using several static jwt keys"
+                );
+                Decoders::Rotating(static_keys)
+            }
+        };
+        if config.selftest {
+            if secrets.is_empty() {
+                warn!("the jwt self-test only checks jwt key secrets, skipping it");
+            } else {
+                match selftest::run(&config, &keys, &secrets).await {
+                    Ok(()) => info!("jwt self-test passed"),
+                    Err(err) => {
+                        error!(error = format!("{err:#}"), "jwt self-test failed");
+                        return Err(rocket);
+                    }
+                }
+            }
+        }
         Ok(rocket.manage(keys))
     })
 }
 
-fn validation(algo: Algorithm) -> Validation {
-    let mut validation = Validation::new(algo);
-    let aud = env::var("AUTH_JWT_AUD");
-    validation.set_audience(&[aud.as_deref().unwrap_or("outerspace.silenlocatelli.com")]);
-    validation
-        .required_spec_claims
-        .insert("tax_platform_apps".into());
-    validation
-}
-
-struct Decoder {
+pub struct Decoder {
     key: DecodingKey,
     validation: Validation,
+    /// Checked here, as jsonwebtoken only requires the registered claims it validates itself.
+    required_claims: Vec<String>,
 }
 
-enum Decoders {
+pub enum Decoders {
     Single(Box<Decoder>),
-    Multiple(HashMap<String, Decoder>),
+    /// Several static keys, like secrets while one is rotated out, tried in order.
+    Rotating(Vec<Decoder>),
+    Multiple(Box<JwksCache>),
+    /// The key set for tokens with a known `kid`, and the static keys for all others.
+    Combined {
+        cache: Box<JwksCache>,
+        static_keys: Vec<Decoder>,
+    },
 }
 
 impl Decoder {
-    fn decode(&self, token: &str) -> anyhow::Result<AccessToken> {
-        Ok(jsonwebtoken::decode(token, &self.key, &self.validation)?.claims)
+    fn new(config: &AuthConfig, key: DecodingKey, algo: Algorithm) -> Self {
+        Decoder {
+            key,
+            validation: config.validation(algo),
+            required_claims: config.required_claims(),
+        }
+    }
+
+    pub fn hs256(config: &AuthConfig, secret: impl AsRef<[u8]>) -> Self {
+        Decoder::new(
+            config,
+            DecodingKey::from_secret(secret.as_ref()),
+            Algorithm::HS256,
+        )
+    }
+
+    /// A decoder for tokens signed with the Ed25519 key whose public key is `pem`.
+    pub fn ed25519(config: &AuthConfig, pem: &[u8]) -> anyhow::Result<Self> {
+        let key = DecodingKey::from_ed_pem(pem).context("invalid ed25519 public key")?;
+        Ok(Decoder::new(config, key, Algorithm::EdDSA))
+    }
+
+    /// A decoder for tokens signed with the RSA key whose public key is `pem`.
+    pub fn rs256(config: &AuthConfig, pem: &[u8]) -> anyhow::Result<Self> {
+        let key = DecodingKey::from_rsa_pem(pem).context("invalid rsa public key")?;
+        Ok(Decoder::new(config, key, Algorithm::RS256))
+    }
+
+    fn algorithm(&self) -> Algorithm {
+        self.validation.algorithms[0]
+    }
+
+    /// The same decoder, expecting `audience` instead of the configured one.
+    #[cfg(test)]
+    pub fn with_audience(mut self, audience: &str) -> Self {
+        self.validation.set_audience(&[audience]);
+        self
+    }
+
+    /// Decodes the token, with the configured validation adjusted by `checks`.
+    fn decode<T: DeserializeOwned>(&self, token: &str, checks: Checks<'_>) -> Result<T, AuthError> {
+        let claims: Map<String, Value> = if checks == Checks::default() {
+            jsonwebtoken::decode(token, &self.key, &self.validation)?.claims
+        } else {
+            let mut validation = self.validation.clone();
+            if let Some(audience) = checks.audience {
+                validation.set_audience(&[audience]);
+                validation.required_spec_claims.insert("aud".into());
+            }
+            if checks.ignore_expiry {
+                validation.validate_exp = false;
+                validation.required_spec_claims.remove("exp");
+            }
+            jsonwebtoken::decode(token, &self.key, &validation)?.claims
+        };
+        if let Some(claim) = self
+            .required_claims
+            .iter()
+            .find(|claim| !claims.contains_key(claim.as_str()))
+        {
+            return Err(JwtError::from(ErrorKind::MissingRequiredClaim(claim.clone())).into());
+        }
+        serde_json::from_value(Value::Object(claims)).map_err(|err| JwtError::from(err).into())
+    }
+}
+
+/// How a token is validated differently from the configured validation.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Checks<'a> {
+    /// Expected instead of the configured audience.
+    pub audience: Option<&'a str>,
+    /// Accepts expired tokens, the signature is still verified.
+    pub ignore_expiry: bool,
+}
+
+/// Tries the decoders for the algorithm of the token in order, until one of them has a matching
+/// signature.
+fn decode_any<T: DeserializeOwned>(
+    decoders: &[Decoder],
+    token: &str,
+    checks: Checks<'_>,
+) -> Result<T, AuthError> {
+    let alg = jsonwebtoken::decode_header(token)?.alg;
+    let mut decoders = decoders
+        .iter()
+        .filter(|decoder| decoder.algorithm() == alg)
+        .peekable();
+    if decoders.peek().is_none() {
+        return Err(AuthError::InvalidToken(format!(
+            "no key for algorithm {alg:?}"
+        )));
+    }
+    let mut result = Err(AuthError::InvalidSignature);
+    for decoder in decoders {
+        result = decoder.decode(token, checks);
+        if !matches!(result, Err(AuthError::InvalidSignature)) {
+            break;
+        }
     }
+    result
 }
 
+/// A validated token, with the header naming the key and algorithm it was signed with.
+pub struct DecodedToken<T = AccessToken> {
+    pub header: Header,
+    pub claims: T,
+}
+
+/// The header of the token that authenticated the request, for the logs.
+#[derive(Default)]
+struct ValidatedHeader(Option<Header>);
+
 impl Decoders {
-    fn decode(&self, token: &str) -> anyhow::Result<AccessToken> {
+    /// Decodes the access token, with the permissions from the claim the `config` names.
+    async fn decode(
+        &self,
+        config: &AuthConfig,
+        token: &str,
+        checks: Checks<'_>,
+    ) -> Result<DecodedToken, AuthError> {
+        let DecodedToken { header, claims } = self.decode_token::<Claims>(token, checks).await?;
+        let claims = AccessToken::from_claims(claims, &config.permissions_claim)
+            .map_err(AuthError::InvalidToken)?;
+        Ok(DecodedToken { header, claims })
+    }
+
+    pub async fn decode_token<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        checks: Checks<'_>,
+    ) -> Result<DecodedToken<T>, AuthError> {
         let header = jsonwebtoken::decode_header(token)?;
-        let decoder: &Decoder = match self {
-            Decoders::Single(decoder) => decoder,
-            Decoders::Multiple(map) => header
-                .kid
-                .and_then(|k| map.get(&k))
-                .ok_or_else(|| anyhow!("unknown token key"))?,
+        let claims = match self {
+            Decoders::Single(decoder) => decoder.decode(token, checks),
+            Decoders::Rotating(decoders) => decode_any(decoders, token, checks),
+            Decoders::Multiple(cache) => {
+                let kid = header.kid.as_ref().ok_or(AuthError::UnknownKey)?;
+                cache.decode(kid, token, checks).await
+            }
+            Decoders::Combined { cache, static_keys } => match &header.kid {
+                Some(kid) => match cache.decode(kid, token, checks).await {
+                    Err(AuthError::UnknownKey) => decode_any(static_keys, token, checks),
+                    result => result,
+                },
+                None => decode_any(static_keys, token, checks),
+            },
+        }?;
+        Ok(DecodedToken { header, claims })
+    }
+}
+
+/// Reads the secrets from the file at `AUTH_HS256_SECRET_FILE`, or else from `AUTH_HS256_SECRET`.
+/// With `AUTH_HS256_MASTER` and `AUTH_HS256_INFO`, the secrets are derived from the master
+/// secrets instead.
+///
+/// Several secrets can be given separated by commas, so that a secret can be rotated. None when
+/// no secret is configured, while a configured one that cannot be read is an error.
+fn load_jwk_secrets(config: &AuthConfig) -> anyhow::Result<Option<Vec<Vec<u8>>>> {
+    if let Some(masters) = &config.hs256_master {
+        let info = config
+            .hs256_info
+            .as_deref()
+            .context("AUTH_HS256_INFO is not set, it is needed with AUTH_HS256_MASTER")?;
+        if masters.is_empty() {
+            anyhow::bail!("no jwt master secret configured");
+        }
+        return masters
+            .iter()
+            .map(|master| derive_secret(master, info))
+            .collect::<anyhow::Result<_>>()
+            .map(Some);
+    }
+    let secrets = match (&config.hs256_secret_file, &config.hs256_secret) {
+        (Some(path), _) => fs::read_to_string(path)
+            .with_context(|| format!("could not read AUTH_HS256_SECRET_FILE '{path}'"))?
+            .split(',')
+            .map(str::trim)
+            .filter(|secret| !secret.is_empty())
+            .map(String::from)
+            .collect(),
+        (None, Some(secrets)) => secrets.clone(),
+        (None, None) => return Ok(None),
+    };
+    if secrets.is_empty() {
+        anyhow::bail!("no jwt key secret configured");
+    }
+    Ok(Some(secrets.into_iter().map(String::into_bytes).collect()))
+}
+
+/// The HS256 secret of this service: the HKDF-SHA256 key derived from `master` with the service
+/// name as `info` and no salt.
+fn derive_secret(master: &str, info: &str) -> anyhow::Result<Vec<u8>> {
+    let mut secret = vec![0; 32];
+    Hkdf::<Sha256>::new(None, master.as_bytes())
+        .expand(info.as_bytes(), &mut secret)
+        .map_err(|err| anyhow::anyhow!("could not derive the jwt key secret: {err}"))?;
+    Ok(secret)
+}
+
+/// The PEM encoded public key, read from `file` when that is given. None when neither is.
+fn load_public_key(pem: &Option<String>, file: &Option<String>) -> anyhow::Result<Option<String>> {
+    match (file, pem) {
+        (Some(path), _) => fs::read_to_string(path)
+            .with_context(|| format!("could not read the public key file '{path}'"))
+            .map(|pem| Some(pem.trim().to_string())),
+        (None, pem) => Ok(pem.as_deref().map(str::trim).map(String::from)),
+    }
+}
+
+/// Fetches the key set, giving up after `AUTH_JWKS_HTTP_TIMEOUT_MS` so a hanging identity
+/// provider cannot block the launch.
+async fn fetch_jwk_set(config: &AuthConfig, url: &str) -> anyhow::Result<JwkSet> {
+    let timeout = Duration::from_millis(config.jwks_http_timeout_ms);
+    let client = reqwest::Client::builder()
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .build()?;
+    let fetch = async {
+        let response = client.get(url).send().await?.error_for_status()?;
+        response.json::<JwkSet>().await
+    };
+    match fetch.await {
+        Ok(key_set) => Ok(key_set),
+        Err(err) if err.is_timeout() => {
+            anyhow::bail!("jwk key set request to {url} timed out after {timeout:?}")
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Fetches the key set, retrying `AUTH_JWKS_FETCH_RETRIES` times with exponential backoff
+/// starting at `AUTH_JWKS_FETCH_BACKOFF_MS`, so a short outage of the identity provider at
+/// launch is survived.
+async fn fetch_jwk_set_with_retries(config: &AuthConfig, url: &str) -> anyhow::Result<JwkSet> {
+    let retries = config.jwks_fetch_retries;
+    let backoff = config.jwks_fetch_backoff_ms;
+    let mut attempt = 0;
+    loop {
+        match fetch_jwk_set(config, url).await {
+            Ok(key_set) => return Ok(key_set),
+            Err(err) if attempt < retries => {
+                let delay = Duration::from_millis(backoff.saturating_mul(1 << attempt.min(16)));
+                attempt += 1;
+                warn!(
+                    attempt,
+                    attempts = retries + 1,
+                    error = %err,
+                    retry_in = ?delay,
+                    "Failed to fetch jwk key set"
+                );
+                sleep(delay).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// The decoders of the key set by key id, or by thumbprint for keys without one. Some identity
+/// providers reuse a key id for several keys, all of which are kept.
+fn jwk_decoders(config: &AuthConfig, key_set: &JwkSet) -> HashMap<String, Vec<Decoder>> {
+    let mut decoders: HashMap<String, Vec<Decoder>> = HashMap::new();
+    for jwk in &key_set.keys {
+        let key = match DecodingKey::from_jwk(jwk) {
+            Ok(key) => key,
+            Err(err) => {
+                warn!(
+                    kid = jwk.common.key_id,
+                    kty = key_type(jwk),
+                    alg = ?jwk.common.algorithm,
+                    error = %err,
+                    "Skipping jwk that is not a valid decoding key"
+                );
+                continue;
+            }
+        };
+        // Keys without an id are used by tokens that name them by their thumbprint instead.
+        let Some(kid) = jwk.common.key_id.clone().or_else(|| jwks::thumbprint(jwk)) else {
+            warn!(
+                kty = key_type(jwk),
+                alg = ?jwk.common.algorithm,
+                "Skipping jwk without a key id"
+            );
+            continue;
         };
-        decoder.decode(token)
+        let decoder = Decoder::new(config, key, jwk_algorithm(jwk));
+        decoders.entry(kid).or_default().push(decoder);
     }
+    let duplicated: Vec<&String> = decoders
+        .iter()
+        .filter(|(_, decoders)| decoders.len() > 1)
+        .map(|(kid, _)| kid)
+        .collect();
+    if !duplicated.is_empty() {
+        warn!(kids = ?duplicated, "jwk key set has several keys with the same key id");
+    }
+    decoders
 }
 
-fn load_jwk_secret() -> Result<Decoder, VarError> {
-    let secret = env::var("AUTH_HS256_SECRET")?;
-    let validation = validation(Algorithm::HS256);
-    let key = DecodingKey::from_secret(secret.as_bytes());
-    Ok(Decoder { key, validation })
+fn key_type(jwk: &Jwk) -> &'static str {
+    match jwk.algorithm {
+        AlgorithmParameters::EllipticCurve(_) => "EC",
+        AlgorithmParameters::RSA(_) => "RSA",
+        AlgorithmParameters::OctetKey(_) => "oct",
+        AlgorithmParameters::OctetKeyPair(_) => "OKP",
+    }
 }
 
-async fn fetch_jwk_set() -> anyhow::Result<HashMap<String, Decoder>> {
-    let url = env::var("AUTH_JWKS_URL")?;
-    let key_set: JwkSet = reqwest::get(url).await?.json::<JwkSet>().await?;
-    Ok(key_set
-        .keys
-        .into_iter()
-        .filter_map(|jwk| {
-            let key = DecodingKey::from_jwk(&jwk).ok()?;
-            let validation = validation(Algorithm::RS256);
-            let kid = jwk.common.key_id?;
-            Some((kid, Decoder { key, validation }))
-        })
-        .collect())
+/// The algorithm a key signs with, from its `alg` or else guessed from its key type.
+fn jwk_algorithm(jwk: &Jwk) -> Algorithm {
+    if let Some(algorithm) = jwk.common.algorithm {
+        return algorithm;
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P384 => Algorithm::ES384,
+            _ => Algorithm::ES256,
+        },
+        AlgorithmParameters::OctetKeyPair(_) => Algorithm::EdDSA,
+        AlgorithmParameters::OctetKey(_) => Algorithm::HS256,
+        AlgorithmParameters::RSA(_) => Algorithm::RS256,
+    }
 }
 
+/// The claims of a validated token.
+///
+/// Deserializing it reads the permissions from the default claim, tokens of requests are decoded
+/// with the claim of the [`AuthConfig`] instead.
 #[allow(unused)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "Claims")]
 pub struct AccessToken {
-    pub email: Option<String>,
+    /// The email claim, `None` when it is not a valid address.
+    pub email: Option<EmailAddress>,
+    /// The name to show when the identity provider sends no given and family name.
+    pub preferred_username: Option<String>,
     user_id: Uuid,
-    #[serde(default)]
-    permissions: Vec<Permission>,
+    permissions: Scopes,
+    /// Scopes the token was issued that this service does not know about.
+    unknown: Vec<String>,
+    /// The roles of the identity provider, whose permissions are added to `permissions`.
+    roles: Vec<String>,
+    /// The environments the token was issued for, unknown ones are dropped.
+    requested_environments: HashSet<Environment>,
+    exp: Option<i64>,
+    iat: Option<i64>,
+    /// When the user last authenticated, which can be long before the token was issued.
+    auth_time: Option<i64>,
+    jti: Option<String>,
 }
 
+/// The claims as they appear in the token, before they are normalized into an [`AccessToken`].
+#[derive(Debug, Deserialize)]
+struct Claims {
+    email: Option<String>,
+    preferred_username: Option<String>,
+    user_id: Option<Uuid>,
+    /// The standard subject claim, the user id when there is no `user_id` claim.
+    sub: Option<String>,
+    permissions: Option<Vec<String>>,
+    /// OAuth2 style space delimited scopes, used when there is no `permissions` array.
+    scope: Option<String>,
+    roles: Option<Vec<String>>,
+    requested_environments: Option<Vec<String>>,
+    exp: Option<i64>,
+    iat: Option<i64>,
+    auth_time: Option<i64>,
+    jti: Option<String>,
+    /// All other claims, where the permissions are when `AUTH_PERMISSIONS_CLAIM` names another.
+    #[serde(flatten)]
+    other: HashMap<String, Value>,
+}
+
+const DEFAULT_PERMISSIONS_CLAIM: &str = "permissions";
+
+impl Claims {
+    /// The permissions under `claim`, ignored when they are not a list of strings.
+    fn take_permissions(&mut self, claim: &str) -> Option<Vec<String>> {
+        if claim == DEFAULT_PERMISSIONS_CLAIM {
+            return self.permissions.take();
+        }
+        let value = self.other.remove(claim)?;
+        serde_json::from_value(value).ok()
+    }
+}
+
+impl Claims {
+    /// The `user_id` claim, or else the `sub` claim when it is a uuid.
+    fn user_id(&self) -> Result<Uuid, String> {
+        match (self.user_id, &self.sub) {
+            (Some(user_id), _) => Ok(user_id),
+            (None, Some(sub)) => sub
+                .parse()
+                .map_err(|err| format!("the sub claim '{sub}' is not a user id: {err}")),
+            (None, None) => Err("the token has neither a user_id nor a sub claim".to_string()),
+        }
+    }
+}
+
+impl TryFrom<Claims> for AccessToken {
+    type Error = String;
+
+    fn try_from(claims: Claims) -> Result<Self, Self::Error> {
+        AccessToken::from_claims(claims, DEFAULT_PERMISSIONS_CLAIM)
+    }
+}
+
+impl AccessToken {
+    /// Normalizes the `claims`, reading the permissions from `permissions_claim`.
+    fn from_claims(mut claims: Claims, permissions_claim: &str) -> Result<Self, String> {
+        let user_id = claims.user_id()?;
+        let permissions = claims.take_permissions(permissions_claim);
+        let scopes = match (permissions, claims.scope) {
+            (Some(permissions), _) => permissions,
+            (None, Some(scope)) => scope.split_whitespace().map(String::from).collect(),
+            (None, None) => Vec::new(),
+        };
+        let mut permissions = Scopes::default();
+        let mut unknown = Vec::new();
+        for scope in scopes {
+            match scope.parse() {
+                Ok(permission) => permissions.insert(permission),
+                Err(_) => unknown.push(scope),
+            }
+        }
+        Ok(Self {
+            email: claims.email.and_then(|email| match parse_email(&email) {
+                Ok(email) => Some(email),
+                Err(err) => {
+                    warn!(user_id = %user_id, error = %err, "Ignoring invalid email claim");
+                    None
+                }
+            }),
+            preferred_username: claims.preferred_username,
+            user_id,
+            permissions,
+            unknown,
+            roles: claims.roles.unwrap_or_default(),
+            requested_environments: claims
+                .requested_environments
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|env| env.parse().ok())
+                .collect(),
+            exp: claims.exp,
+            iat: claims.iat,
+            auth_time: claims.auth_time,
+            jti: claims.jti,
+        })
+    }
+}
+
+#[allow(unused)]
+impl AccessToken {
+    pub fn user_id(&self) -> Uuid {
+        self.user_id
+    }
+
+    /// When the token expires.
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.exp.and_then(|exp| DateTime::from_timestamp(exp, 0))
+    }
+
+    /// When the token was issued.
+    pub fn issued_at(&self) -> Option<DateTime<Utc>> {
+        self.iat.and_then(|iat| DateTime::from_timestamp(iat, 0))
+    }
+
+    /// When the user last authenticated, from the `auth_time` claim.
+    pub fn authenticated_at(&self) -> Option<DateTime<Utc>> {
+        self.auth_time
+            .and_then(|auth_time| DateTime::from_timestamp(auth_time, 0))
+    }
+
+    /// Whether the token was issued after the sessions of `user` were last ended.
+    ///
+    /// Tokens without `iat` cannot tell, so they are only valid while no sessions were ended.
+    pub fn is_session_valid(&self, user: &User) -> bool {
+        match (user.sessions_valid_after, self.issued_at()) {
+            (None, _) => true,
+            (Some(after), Some(issued_at)) => issued_at >= after,
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Whether the token expires within `duration` from now, never for tokens without `exp`.
+    pub fn is_expired_within(&self, duration: TimeDelta) -> bool {
+        self.expires_at()
+            .is_some_and(|expires_at| expires_at <= Utc::now() + duration)
+    }
+}
+
+/// The token from the `authorization` header, or else from the auth cookie, or else from the
+/// query when that is allowed.
+fn bearer_token<'r>(request: &'r Request<'_>, config: &AuthConfig) -> Option<&'r str> {
+    request
+        .headers()
+        .get("authorization")
+        .next()
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .or_else(|| {
+            request
+                .cookies()
+                .get(&config.cookie_name)
+                .map(|cookie| cookie.value())
+        })
+        .or_else(|| {
+            if !config.allow_query_token {
+                return None;
+            }
+            request.query_value::<&str>("access_token")?.ok()
+        })
+}
+
+/// Refuses tokens longer than [`AuthConfig::max_token_bytes`] before they are decoded.
+fn check_token_length(config: &AuthConfig, token: &str) -> Result<(), AuthError> {
+    let max_token_bytes = config.max_token_bytes;
+    if token.len() > max_token_bytes {
+        return Err(AuthError::InvalidToken(format!(
+            "token is longer than {max_token_bytes} bytes"
+        )));
+    }
+    Ok(())
+}
+
+/// The address invalid tokens are counted against, see [`AuthConfig::ratelimit_trust_proxy`].
+fn client_address(request: &Request<'_>, config: &AuthConfig) -> Option<IpAddr> {
+    if config.ratelimit_trust_proxy {
+        request.client_ip()
+    } else {
+        request.remote().map(|remote| remote.ip())
+    }
+}
+
+/// Counts an invalid token against the client, once per request however often it is decoded.
+fn record_failed_attempt(request: &Request<'_>, config: &AuthConfig) {
+    struct Recorded;
+    let failed_attempts = request.rocket().state::<FailedAttempts>();
+    if let (Some(failed_attempts), Some(ip)) = (failed_attempts, client_address(request, config)) {
+        request.local_cache(|| {
+            failed_attempts.fail(ip);
+            Recorded
+        });
+    }
+}
+
+/// The route of the request for the logs, without any of its query or path values.
+fn route(request: &Request<'_>) -> Option<String> {
+    request.route().map(|route| route.uri.to_string())
+}
+
+/// The outcome of authenticating the request, so that the guards built on [`AccessToken`]
+/// decode, count and rate limit its token only once.
+struct Authenticated(Outcome<AccessToken, AuthError>);
+
 #[async_trait]
 impl<'r> FromRequest<'r> for AccessToken {
-    type Error = anyhow::Error;
+    type Error = AuthError;
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let Some(token) = request
-            .headers()
-            .get("authorization")
-            .next()
-            .and_then(|v| v.strip_prefix("Bearer "))
-        else {
-            return Outcome::Error((Status::Unauthorized, anyhow!("missing authorization token")));
-        };
-        let Outcome::Success(decoders) = request.guard::<&State<Decoders>>().await else {
-            error!("no jwt decoding key found");
-            return Outcome::Forward(Status::Ok);
-        };
-        match decoders.decode(token) {
-            Ok(token) => Outcome::Success(token),
-            Err(err) => {
-                warn!("Invalid token: '{token}'");
-                return Outcome::Error((Status::Unauthorized, err));
+        request
+            .local_cache_async(async { Authenticated(authenticate(request, None).await) })
+            .await
+            .0
+            .clone()
+    }
+}
+
+/// Decodes the token of the request, expecting `audience` instead of the configured one when
+/// given.
+async fn authenticate(
+    request: &Request<'_>,
+    audience: Option<&str>,
+) -> Outcome<AccessToken, AuthError> {
+    if public::is_public(request) {
+        return Outcome::Forward(Status::Unauthorized);
+    }
+    let Some(config) = request.rocket().state::<AuthConfig>() else {
+        return AuthError::Unavailable.outcome(request);
+    };
+    let Some(token) = bearer_token(request, config) else {
+        counter!("auth_failure_total", "reason" => AuthError::MissingToken.reason()).increment(1);
+        return AuthError::MissingToken.outcome(request);
+    };
+    let failed_attempts = request.rocket().state::<FailedAttempts>();
+    let client = client_address(request, config);
+    if let (Some(failed_attempts), Some(ip)) = (failed_attempts, client)
+        && failed_attempts.is_limited(ip)
+    {
+        counter!("auth_failure_total", "reason" => AuthError::RateLimited.reason()).increment(1);
+        return AuthError::RateLimited.outcome(request);
+    }
+    if let Err(err) = check_token_length(config, token) {
+        counter!("auth_failure_total", "reason" => err.reason()).increment(1);
+        record_failed_attempt(request, config);
+        return err.outcome(request);
+    }
+    let Outcome::Success(decoders) = request.guard::<&State<Decoders>>().await else {
+        return AuthError::Unavailable.outcome(request);
+    };
+    let checks = Checks {
+        audience,
+        ..Checks::default()
+    };
+    let started = Instant::now();
+    let decoded = decoders.decode(config, token, checks).await;
+    request.local_cache(|| DecodeTime(Some(started.elapsed())));
+    let decoded = decoded.and_then(|decoded| {
+        request.local_cache(|| ValidatedHeader(Some(decoded.header)));
+        let token = decoded.claims;
+        match request.rocket().state::<RevokedTokens>() {
+            Some(revoked) => revoked.check(token),
+            None => Ok(token),
+        }
+    });
+    let decoded = match request.rocket().state::<RoleMap>() {
+        Some(roles) => decoded.map(|token| roles.expand(token)),
+        None => decoded,
+    };
+    let decoded = match decoded {
+        Ok(token) => check_account(request, token).await,
+        Err(err) => Err(err),
+    };
+    match decoded {
+        Ok(token) => {
+            counter!("auth_success_total").increment(1);
+            debug!(
+                request_id = %RequestId::of(request),
+                route = route(request),
+                user_id = %token.user_id,
+                "Authenticated token"
+            );
+            Outcome::Success(token)
+        }
+        Err(err) => {
+            counter!("auth_failure_total", "reason" => err.reason()).increment(1);
+            let kid = jsonwebtoken::decode_header(token).ok().and_then(|h| h.kid);
+            warn!(
+                request_id = %RequestId::of(request),
+                route = route(request),
+                kid,
+                error_kind = err.code(),
+                error = %err,
+                "Invalid token"
+            );
+            if !err.status().class().is_server_error() {
+                record_failed_attempt(request, config);
             }
+            err.outcome(request)
         }
     }
 }
 
+/// The account of the user the token was issued to, `None` when they have none.
+#[derive(Default)]
+struct Account(Option<User>);
+
+/// Refuses the tokens of deleted users and those issued before the sessions of their user were
+/// ended, so that every guard built on [`AccessToken`] does. The account is kept for
+/// [`AuthorizedUser`].
+async fn check_account(
+    request: &Request<'_>,
+    token: AccessToken,
+) -> Result<AccessToken, AuthError> {
+    let Outcome::Success(users) = request.guard::<&State<Users>>().await else {
+        error!(
+            request_id = %RequestId::of(request),
+            route = route(request),
+            "no user repository found"
+        );
+        return Err(AuthError::Internal("no user repository found".into()));
+    };
+    let user = users
+        .find_user_with_deleted(token.user_id)
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    match &user {
+        Some(user) if user.deleted_at.is_some() => return Err(AuthError::UserDeleted),
+        Some(user) if !token.is_session_valid(user) => return Err(AuthError::Revoked),
+        _ => {}
+    }
+    request.local_cache(|| Account(user));
+    Ok(token)
+}
+
+/// The user the token was issued to, without checking that they still have an account.
+impl From<AccessToken> for AuthorizedUser {
+    fn from(token: AccessToken) -> Self {
+        Self { id: token.user_id }
+    }
+}
+
 #[async_trait]
 impl<'r> FromRequest<'r> for AuthorizedUser {
-    type Error = anyhow::Error;
+    type Error = AuthError;
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
         let token = match request.guard::<AccessToken>().await {
             Outcome::Success(token) => token,
@@ -147,46 +946,195 @@ impl<'r> FromRequest<'r> for AuthorizedUser {
             Outcome::Forward(x) => return Outcome::Forward(x),
         };
 
-        warn!("request user not found in database. Using JWT token content");
-        let user = AuthorizedUser::create(token.user_id);
+        let Account(user) = request.local_cache(Account::default);
+        let Some(user) = user else {
+            return AuthError::UserNotFound.outcome(request);
+        };
+        let ValidatedHeader(header) = request.local_cache(ValidatedHeader::default);
+        info!(
+            request_id = %RequestId::of(request),
+            route = route(request),
+            user_id = %user.id,
+            kid = header.as_ref().and_then(|h| h.kid.as_deref()),
+            alg = ?header.as_ref().map(|h| h.alg),
+            "Authorized user"
+        );
+        Outcome::Success(AuthorizedUser::from(token))
+    }
+}
+
+/// The authenticated user of the request, if any, for routes that also serve anonymous callers.
+///
+/// Missing, invalid and expired tokens all give `None`, only server side failures are errors.
+#[allow(unused)]
+pub struct MaybeUser(pub Option<AuthorizedUser>);
 
-        match user {
-            Ok(user) => Outcome::Success(user),
-            Err(err) => Outcome::Error((Status::Forbidden, err)),
+#[async_trait]
+impl<'r> FromRequest<'r> for MaybeUser {
+    type Error = AuthError;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.guard::<AuthorizedUser>().await {
+            Outcome::Success(user) => Outcome::Success(MaybeUser(Some(user))),
+            Outcome::Error((status, err)) if status.class().is_server_error() => {
+                Outcome::Error((status, err))
+            }
+            Outcome::Error(_) | Outcome::Forward(_) => Outcome::Success(MaybeUser(None)),
         }
     }
 }
 
+/// How long before its expiry a token is reported as expiring soon.
+const EXPIRING_SOON: TimeDelta = TimeDelta::minutes(5);
+
 #[derive(Debug, Serialize)]
 pub struct MeUser {
     pub id: Uuid,
+    /// Hints the client to refresh the token before it expires.
+    pub token_expiring_soon: bool,
+}
+
+#[get("/users/me")]
+pub fn get_me(req_user: AuthorizedUser, token: AccessToken) -> Json<MeUser> {
+    Json(MeUser {
+        id: req_user.id,
+        token_expiring_soon: token.is_expired_within(EXPIRING_SOON),
+    })
+}
+
+#[get("/users/me/permissions")]
+pub fn get_my_permissions(token: AccessToken) -> Json<Scopes> {
+    Json(token.permissions)
 }
 
-impl From<AuthorizedUser> for MeUser {
-    fn from(value: AuthorizedUser) -> Self {
-        Self { id: value.id }
+/// An RFC 7662 style introspection response; only valid tokens get this far.
+#[derive(Debug, Serialize)]
+pub struct Introspection {
+    pub active: bool,
+    pub user_id: Uuid,
+    pub email: Option<String>,
+    pub permissions: Scopes,
+    pub exp: Option<i64>,
+}
+
+impl From<AccessToken> for Introspection {
+    fn from(value: AccessToken) -> Self {
+        Self {
+            active: true,
+            user_id: value.user_id,
+            email: value.email.map(|email| email.to_string()),
+            permissions: value.permissions,
+            exp: value.exp,
+        }
     }
 }
 
-#[get("/users/me")]
-pub fn get_me(req_user: AuthorizedUser) -> Json<MeUser> {
-    Json(req_user.into())
+#[post("/auth/introspect")]
+pub fn introspect(token: AccessToken) -> Json<Introspection> {
+    Json(token.into())
 }
 
 #[async_trait]
 impl<'r> FromRequest<'r> for AdminUser {
-    type Error = anyhow::Error;
+    type Error = AuthError;
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
         match request.guard::<AccessToken>().await {
             Outcome::Success(token) => match token.to_admin() {
                 Ok(user) => Outcome::Success(user),
-                Err(_) => Outcome::Error((
-                    Status::Forbidden,
-                    anyhow!("you do not have enough permission"),
-                )),
+                Err(err) => AuthError::from(err).outcome(request),
             },
             Outcome::Error(err) => Outcome::Error(err),
             Outcome::Forward(x) => Outcome::Forward(x),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_derived_with_hkdf_sha256() {
+        // Test case 3 of RFC 5869, whose salt is empty like ours.
+        let secret = derive_secret(&"\x0b".repeat(22), "").unwrap();
+        let hex: String = secret.iter().map(|byte| format!("{byte:02x}")).collect();
+        assert_eq!(
+            hex,
+            "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d"
+        );
+    }
+
+    #[test]
+    fn unreadable_secret_file_is_an_error() {
+        assert!(load_jwk_secrets(&AuthConfig::default()).unwrap().is_none());
+
+        let config = AuthConfig {
+            hs256_secret: Some(vec!["secret".into()]),
+            hs256_secret_file: Some("/nonexistent/jwt-secret".into()),
+            ..AuthConfig::default()
+        };
+        let err = load_jwk_secrets(&config).unwrap_err();
+        assert!(err.to_string().contains("AUTH_HS256_SECRET_FILE"), "{err}");
+    }
+
+    #[test]
+    fn authorized_user_is_the_user_of_the_token() {
+        let user_id = Uuid::new_v4();
+        let token: AccessToken =
+            serde_json::from_value(serde_json::json!({ "user_id": user_id })).unwrap();
+        assert_eq!(AuthorizedUser::from(token).id, user_id);
+    }
+
+    #[test]
+    fn user_id_falls_back_to_the_sub_claim() {
+        let decode = |claims| serde_json::from_value::<AccessToken>(claims);
+        let user_id = Uuid::new_v4();
+
+        let token = decode(serde_json::json!({ "sub": user_id.to_string() })).unwrap();
+        assert_eq!(token.user_id(), user_id);
+
+        let err = decode(serde_json::json!({ "sub": "auth0|42" })).unwrap_err();
+        assert!(err.to_string().contains("not a user id"), "{err}");
+        let err = decode(serde_json::json!({})).unwrap_err();
+        assert!(
+            err.to_string().contains("neither a user_id nor a sub"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn malformed_email_claim_is_dropped() {
+        let token = |email: &str| -> AccessToken {
+            serde_json::from_value(serde_json::json!({
+                "user_id": Uuid::new_v4(),
+                "email": email,
+            }))
+            .unwrap()
+        };
+
+        assert_eq!(token("not an email").email, None);
+        assert_eq!(
+            token("ada@example.com")
+                .email
+                .map(|email| email.to_string()),
+            Some("ada@example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn permissions_are_read_from_a_namespaced_claim() {
+        let mut claims: Claims = serde_json::from_value(serde_json::json!({
+            "user_id": Uuid::new_v4(),
+            "permissions": ["read:users"],
+            "https://outerspace/permissions": ["admin"],
+        }))
+        .unwrap();
+
+        let namespaced = claims.take_permissions("https://outerspace/permissions");
+        assert_eq!(namespaced, Some(vec!["admin".to_string()]));
+        assert_eq!(
+            claims.take_permissions("permissions"),
+            Some(vec!["read:users".to_string()])
+        );
+        assert_eq!(claims.take_permissions("https://other/permissions"), None);
+    }
+}
@@ -1,7 +1,14 @@
-use crate::authentication::AccessToken;
+use std::{fmt, marker::PhantomData, str::FromStr};
+
+use crate::authentication::{AccessToken, error::AuthError};
 use crate::model::read::AdminUser;
-use rocket::http::Status;
-use serde::Deserialize;
+use rocket::{
+    Request,
+    http::Status,
+    request::{FromRequest, Outcome},
+    response::{self, Responder},
+};
+use serde::{Deserialize, Serialize, de::IntoDeserializer};
 
 #[derive(PartialEq, Eq, Debug)]
 pub struct InsufficientScope(String);
@@ -12,6 +19,19 @@ impl From<InsufficientScope> for Status {
     }
 }
 
+impl From<InsufficientScope> for AuthError {
+    fn from(value: InsufficientScope) -> Self {
+        AuthError::InsufficientScope(value.0)
+    }
+}
+
+/// Responds like the guards do, with a 403 whose body says which scopes were missing.
+impl<'r> Responder<'r, 'static> for InsufficientScope {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        AuthError::from(self).respond_to(request)
+    }
+}
+
 impl From<anyhow::Error> for InsufficientScope {
     fn from(value: anyhow::Error) -> Self {
         Self(value.to_string())
@@ -23,29 +43,336 @@ impl AccessToken {
         &self,
         expected_scope: Permission,
     ) -> Result<&Self, InsufficientScope> {
-        if self
-            .permissions
+        self.permissions.require(expected_scope).map(|_| self)
+    }
+
+    /// Requires at least one of the `scopes`.
+    #[allow(unused)]
+    pub fn require_any_permission(
+        &self,
+        scopes: &[Permission],
+    ) -> Result<&Self, InsufficientScope> {
+        self.permissions.require_any(scopes).map(|_| self)
+    }
+
+    /// Requires every one of the `scopes`.
+    #[allow(unused)]
+    pub fn require_all_permissions(
+        &self,
+        scopes: &[Permission],
+    ) -> Result<&Self, InsufficientScope> {
+        self.permissions.require_all(scopes).map(|_| self)
+    }
+
+    pub fn to_admin(&self) -> Result<AdminUser, InsufficientScope> {
+        self.require_permission(Permission::Admin)
+            .map(|token| AdminUser { id: token.user_id })
+    }
+}
+
+/// The permissions a token was issued with, where [`Permission::Admin`] implies all others.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct Scopes(Vec<Permission>);
+
+impl Scopes {
+    pub fn insert(&mut self, permission: Permission) {
+        if !self.0.contains(&permission) {
+            self.0.push(permission);
+        }
+    }
+
+    pub fn contains(&self, expected_scope: Permission) -> bool {
+        self.0
+            .iter()
+            .any(|&scope| scope == expected_scope || scope == Permission::Admin)
+    }
+
+    pub fn contains_any(&self, scopes: &[Permission]) -> bool {
+        scopes.iter().any(|&scope| self.contains(scope))
+    }
+
+    pub fn contains_all(&self, scopes: &[Permission]) -> bool {
+        scopes.iter().all(|&scope| self.contains(scope))
+    }
+
+    pub fn require(&self, expected_scope: Permission) -> Result<(), InsufficientScope> {
+        if self.contains(expected_scope) {
+            Ok(())
+        } else {
+            Err(InsufficientScope(format!(
+                "user needs {expected_scope}, but has only: {}",
+                bracketed(&self.0)
+            )))
+        }
+    }
+
+    pub fn require_any(&self, scopes: &[Permission]) -> Result<(), InsufficientScope> {
+        if self.contains_any(scopes) {
+            Ok(())
+        } else {
+            Err(InsufficientScope(format!(
+                "user needs one of: {}, but has only: {}",
+                bracketed(scopes),
+                bracketed(&self.0)
+            )))
+        }
+    }
+
+    pub fn require_all(&self, scopes: &[Permission]) -> Result<(), InsufficientScope> {
+        let missing: Vec<Permission> = scopes
             .iter()
             .copied()
-            .any(|scope| scope == expected_scope)
-        {
-            Ok(self)
+            .filter(|&scope| !self.contains(scope))
+            .collect();
+        if missing.is_empty() {
+            Ok(())
         } else {
             Err(InsufficientScope(format!(
-                "user has only: {allowed_scope:?}",
-                allowed_scope = self.permissions
+                "user is missing: {}",
+                bracketed(&missing)
             )))
         }
     }
+}
 
-    pub fn to_admin(&self) -> Result<AdminUser, InsufficientScope> {
-        self.require_permission(Permission::Admin)
-            .map(|_| AdminUser)
+/// The scope strings of the `permissions`, like `[read:users, write:users]`.
+fn bracketed(permissions: &[Permission]) -> String {
+    let scopes: Vec<&str> = permissions.iter().map(Permission::as_str).collect();
+    format!("[{}]", scopes.join(", "))
+}
+
+impl FromIterator<Permission> for Scopes {
+    fn from_iter<I: IntoIterator<Item = Permission>>(iter: I) -> Self {
+        let mut scopes = Scopes::default();
+        for permission in iter {
+            scopes.insert(permission);
+        }
+        scopes
+    }
+}
+
+impl IntoIterator for Scopes {
+    type Item = Permission;
+    type IntoIter = std::vec::IntoIter<Permission>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+impl<'a> IntoIterator for &'a Scopes {
+    type Item = &'a Permission;
+    type IntoIter = std::slice::Iter<'a, Permission>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Permission {
     #[serde(rename = "admin")]
     Admin,
+    #[serde(rename = "read:users")]
+    ReadUsers,
+    #[serde(rename = "write:users")]
+    WriteUsers,
+    #[serde(rename = "manage:environments")]
+    ManageEnvironments,
+    #[serde(rename = "view:billing")]
+    ViewBilling,
+}
+
+impl Permission {
+    /// The scope string the permission is issued as.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::Admin => "admin",
+            Permission::ReadUsers => "read:users",
+            Permission::WriteUsers => "write:users",
+            Permission::ManageEnvironments => "manage:environments",
+            Permission::ViewBilling => "view:billing",
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Permission {
+    type Err = serde::de::value::Error;
+
+    /// Parses the scope string the permission is issued as.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::deserialize(s.into_deserializer())
+    }
+}
+
+/// A permission that can be required by a [`PermissionGuard`].
+#[allow(unused)]
+pub trait RequiredPermission {
+    const PERMISSION: Permission;
+}
+
+macro_rules! permission_markers {
+    ($($marker:ident),* $(,)?) => {
+        $(
+            #[doc = concat!("Marker for [`Permission::", stringify!($marker), "`].")]
+            #[allow(unused)]
+            pub struct $marker;
+
+            impl RequiredPermission for $marker {
+                const PERMISSION: Permission = Permission::$marker;
+            }
+        )*
+    };
+}
+
+permission_markers!(
+    Admin,
+    ReadUsers,
+    WriteUsers,
+    ManageEnvironments,
+    ViewBilling
+);
+
+/// The access token of a request that was granted the permission `P`.
+#[allow(unused)]
+pub struct PermissionGuard<P: RequiredPermission> {
+    pub token: AccessToken,
+    permission: PhantomData<P>,
+}
+
+#[async_trait]
+impl<'r, P: RequiredPermission> FromRequest<'r> for PermissionGuard<P> {
+    type Error = AuthError;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = match request.guard::<AccessToken>().await {
+            Outcome::Success(token) => token,
+            Outcome::Error(err) => return Outcome::Error(err),
+            Outcome::Forward(x) => return Outcome::Forward(x),
+        };
+        match token.require_permission(P::PERMISSION) {
+            Ok(_) => Outcome::Success(PermissionGuard {
+                token,
+                permission: PhantomData,
+            }),
+            Err(err) => AuthError::from(err).outcome(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    fn token(permissions: &[&str]) -> AccessToken {
+        serde_json::from_value(json!({
+            "user_id": Uuid::new_v4(),
+            "permissions": permissions,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn admin_satisfies_any_permission() {
+        assert!(
+            token(&["admin"])
+                .require_permission(Permission::WriteUsers)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn any_permission_needs_one_match() {
+        let token = token(&["read:users"]);
+        assert!(
+            token
+                .require_any_permission(&[Permission::WriteUsers, Permission::ReadUsers])
+                .is_ok()
+        );
+        assert!(
+            token
+                .require_any_permission(&[Permission::WriteUsers, Permission::ViewBilling])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn all_permissions_lists_the_missing_ones() {
+        let err = token(&["read:users"])
+            .require_all_permissions(&[Permission::ReadUsers, Permission::WriteUsers])
+            .unwrap_err();
+        assert_eq!(
+            err,
+            InsufficientScope("user is missing: [write:users]".into())
+        );
+    }
+
+    #[test]
+    fn scopes_are_read_from_a_space_delimited_string() {
+        let token: AccessToken = serde_json::from_value(json!({
+            "user_id": Uuid::new_v4(),
+            "scope": "read:users openid view:billing",
+        }))
+        .unwrap();
+        assert_eq!(
+            token.permissions,
+            Scopes(vec![Permission::ReadUsers, Permission::ViewBilling])
+        );
+    }
+
+    #[test]
+    fn unknown_permissions_are_collected() {
+        let token = token(&["read:users", "launch:rockets"]);
+        assert_eq!(token.permissions, Scopes(vec![Permission::ReadUsers]));
+        assert_eq!(token.unknown, vec!["launch:rockets".to_string()]);
+    }
+
+    #[test]
+    fn other_permissions_are_not_implied() {
+        assert!(
+            token(&["read:users"])
+                .require_permission(Permission::WriteUsers)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn permissions_display_as_they_are_serialized() {
+        for permission in [
+            Permission::Admin,
+            Permission::ReadUsers,
+            Permission::WriteUsers,
+            Permission::ManageEnvironments,
+            Permission::ViewBilling,
+        ] {
+            assert_eq!(json!(permission), json!(permission.to_string()));
+            assert_eq!(permission.as_str().parse::<Permission>(), Ok(permission));
+        }
+    }
+
+    #[test]
+    fn scopes_are_deduplicated() {
+        let scopes: Scopes = [Permission::ReadUsers, Permission::ReadUsers]
+            .into_iter()
+            .collect();
+        assert_eq!(scopes.into_iter().count(), 1);
+    }
+
+    #[test]
+    fn scopes_contain_all_needs_every_match() {
+        let scopes: Scopes = [Permission::ReadUsers, Permission::ViewBilling]
+            .into_iter()
+            .collect();
+        assert!(scopes.contains_all(&[Permission::ReadUsers, Permission::ViewBilling]));
+        assert!(!scopes.contains_all(&[Permission::ReadUsers, Permission::WriteUsers]));
+        assert!(Scopes(vec![Permission::Admin]).contains_all(&[Permission::WriteUsers]));
+    }
 }
@@ -0,0 +1,77 @@
+use rocket::{
+    Request,
+    fairing::{AdHoc, Fairing},
+};
+use tracing::info;
+
+use super::config::AuthConfig;
+
+/// The path prefixes from `AUTH_PUBLIC_ROUTES` that never require authentication, whatever
+/// guards their routes use.
+pub struct PublicRoutes(Vec<String>);
+
+/// Whether the path of a request is public, worked out once per request.
+struct PublicRoute(bool);
+
+impl PublicRoutes {
+    pub fn new(prefixes: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self(prefixes.into_iter().map(Into::into).collect())
+    }
+
+    /// Whether `path` is one of the prefixes or below one, so `/docs` matches `/docs/api` but
+    /// not `/docsearch`.
+    pub fn matches(&self, path: &str) -> bool {
+        self.0.iter().any(|prefix| {
+            path.strip_prefix(prefix.trim_end_matches('/'))
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+}
+
+/// Whether authentication is skipped for the request, in which case its token guards forward.
+pub fn is_public(request: &Request<'_>) -> bool {
+    request
+        .local_cache(|| {
+            let path = request.uri().path();
+            PublicRoute(
+                request
+                    .rocket()
+                    .state::<PublicRoutes>()
+                    .is_some_and(|routes| routes.matches(path.as_str())),
+            )
+        })
+        .0
+}
+
+/// Manages the public routes of the [`AuthConfig`], so it has to be attached after the
+/// [`fairing`](super::fairing) reading it.
+pub fn fairing() -> impl Fairing {
+    AdHoc::on_ignite("Public routes", |rocket| async {
+        if rocket.state::<PublicRoutes>().is_some() {
+            return rocket;
+        }
+        let prefixes = rocket
+            .state::<AuthConfig>()
+            .and_then(|config| config.public_routes.clone());
+        let routes = PublicRoutes::new(prefixes.unwrap_or_default());
+        if !routes.0.is_empty() {
+            info!(routes = ?routes.0, "Serving routes without authentication");
+        }
+        rocket.manage(routes)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        let routes = PublicRoutes::new(["/docs", "/health/"]);
+        assert!(routes.matches("/docs"));
+        assert!(routes.matches("/docs/api"));
+        assert!(routes.matches("/health"));
+        assert!(!routes.matches("/docsearch"));
+        assert!(!routes.matches("/users"));
+    }
+}
@@ -0,0 +1,58 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Above this many tracked clients, the ones whose window has passed are forgotten.
+const PRUNE_ABOVE: usize = 10_000;
+
+/// Counts the invalid tokens each client sent, so a client that sent
+/// `AUTH_RATELIMIT_MAX_FAILURES` within `AUTH_RATELIMIT_WINDOW_SECS` is refused until the window
+/// has passed. Only managed when `AUTH_RATELIMIT_MAX_FAILURES` is above 0.
+///
+/// Valid tokens do not reset the count, or one valid token would allow unlimited guesses.
+pub struct FailedAttempts {
+    window: Duration,
+    max_failures: u32,
+    clients: Mutex<HashMap<IpAddr, Attempts>>,
+}
+
+struct Attempts {
+    since: Instant,
+    failures: u32,
+}
+
+impl FailedAttempts {
+    pub fn new(window: Duration, max_failures: u32) -> Self {
+        Self {
+            window,
+            max_failures,
+            clients: Mutex::default(),
+        }
+    }
+
+    pub fn is_limited(&self, ip: IpAddr) -> bool {
+        let clients = self.clients.lock().expect("rate limit lock poisoned");
+        clients.get(&ip).is_some_and(|attempts| {
+            attempts.since.elapsed() < self.window && attempts.failures >= self.max_failures
+        })
+    }
+
+    pub fn fail(&self, ip: IpAddr) {
+        let mut clients = self.clients.lock().expect("rate limit lock poisoned");
+        if clients.len() > PRUNE_ABOVE {
+            clients.retain(|_, attempts| attempts.since.elapsed() < self.window);
+        }
+        let attempts = clients.entry(ip).or_insert(Attempts {
+            since: Instant::now(),
+            failures: 0,
+        });
+        if attempts.since.elapsed() >= self.window {
+            attempts.since = Instant::now();
+            attempts.failures = 0;
+        }
+        attempts.failures += 1;
+    }
+}
@@ -0,0 +1,94 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use rocket::{
+    State,
+    http::{Cookie, CookieJar, Status},
+};
+use serde::Deserialize;
+
+use super::{AccessToken, config::AuthConfig, error::AuthError};
+use crate::{body::JsonBody, model::read::AdminUser};
+
+/// How long a token without `exp` stays revoked, as it cannot tell when it stops being valid.
+const REVOKED_WITHOUT_EXPIRY: TimeDelta = TimeDelta::days(1);
+
+/// The `jti`s of tokens that were revoked before they expired, with when they expire. Expired
+/// tokens are refused anyway, so they are forgotten whenever another token is revoked.
+#[derive(Debug, Default)]
+pub struct RevokedTokens(RwLock<HashMap<String, DateTime<Utc>>>);
+
+impl RevokedTokens {
+    pub fn revoke(&self, jti: String, expires_at: Option<DateTime<Utc>>) {
+        let now = Utc::now();
+        let mut revoked = self.0.write().expect("revoked tokens lock poisoned");
+        revoked.retain(|_, expires_at| *expires_at > now);
+        revoked.insert(
+            jti,
+            expires_at.unwrap_or_else(|| now + REVOKED_WITHOUT_EXPIRY),
+        );
+    }
+
+    pub fn check(&self, token: AccessToken) -> Result<AccessToken, AuthError> {
+        let revoked = self.0.read().expect("revoked tokens lock poisoned");
+        match &token.jti {
+            Some(jti) if revoked.contains_key(jti) => Err(AuthError::Revoked),
+            _ => Ok(token),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Revoke {
+    pub jti: String,
+    /// The `exp` of the token, until which it is kept revoked.
+    pub exp: Option<i64>,
+}
+
+#[post("/auth/revoke", data = "<revoke>")]
+pub fn revoke(
+    _admin: AdminUser,
+    revoked: &State<RevokedTokens>,
+    revoke: JsonBody<Revoke>,
+) -> Status {
+    let revoke = revoke.into_inner();
+    let expires_at = revoke.exp.and_then(|exp| DateTime::from_timestamp(exp, 0));
+    revoked.revoke(revoke.jti, expires_at);
+    Status::NoContent
+}
+
+/// Ends the session by removing the auth cookie and revoking the token. Succeeds without a
+/// cookie or a valid token too, so the frontend can always call it.
+#[post("/auth/logout")]
+pub fn logout(
+    token: Option<AccessToken>,
+    config: &State<AuthConfig>,
+    revoked: &State<RevokedTokens>,
+    cookies: &CookieJar<'_>,
+) -> Status {
+    cookies.remove(Cookie::from(config.cookie_name.clone()));
+    if let Some(token) = token {
+        let expires_at = token.expires_at();
+        if let Some(jti) = token.jti {
+            revoked.revoke(jti, expires_at);
+        }
+    }
+    Status::NoContent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_tokens_are_forgotten() {
+        let revoked = RevokedTokens::default();
+        revoked.revoke("old".into(), Some(Utc::now() - TimeDelta::minutes(1)));
+        revoked.revoke("new".into(), None);
+
+        let revoked = revoked.0.read().unwrap();
+        assert!(!revoked.contains_key("old"));
+        assert!(revoked.contains_key("new"));
+    }
+}
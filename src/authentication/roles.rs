@@ -0,0 +1,53 @@
+use std::{collections::HashMap, fs};
+
+use anyhow::Context;
+
+use super::{AccessToken, config::AuthConfig, permissions::Permission};
+
+/// The permissions each role of the identity provider grants, from the JSON object in the file
+/// at `AUTH_ROLE_MAP_FILE` or else in `AUTH_ROLE_MAP`, e.g. `{"support": ["read:users"]}`.
+#[derive(Debug, Default)]
+pub struct RoleMap(HashMap<String, Vec<Permission>>);
+
+impl RoleMap {
+    pub fn load(config: &AuthConfig) -> anyhow::Result<Self> {
+        let json = match (&config.role_map_file, &config.role_map) {
+            (Some(path), _) => fs::read_to_string(path)
+                .with_context(|| format!("could not read AUTH_ROLE_MAP_FILE '{path}'"))?,
+            (None, Some(json)) => json.clone(),
+            (None, None) => return Ok(Self::default()),
+        };
+        let roles = serde_json::from_str(&json).context("invalid role map")?;
+        Ok(Self(roles))
+    }
+
+    /// Adds the permissions of the roles of the token to its own.
+    pub fn expand(&self, mut token: AccessToken) -> AccessToken {
+        for role in &token.roles {
+            for &permission in self.0.get(role).into_iter().flatten() {
+                token.permissions.insert(permission);
+            }
+        }
+        token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    #[test]
+    fn roles_grant_their_permissions() {
+        let roles = RoleMap(serde_json::from_value(json!({ "support": ["read:users"] })).unwrap());
+        let token: AccessToken = serde_json::from_value(json!({
+            "user_id": Uuid::new_v4(),
+            "roles": ["support", "engineer"],
+        }))
+        .unwrap();
+        let token = roles.expand(token);
+        assert!(token.require_permission(Permission::ReadUsers).is_ok());
+        assert!(token.require_permission(Permission::WriteUsers).is_err());
+    }
+}
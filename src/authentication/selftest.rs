@@ -0,0 +1,53 @@
+use anyhow::Context;
+use chrono::{TimeDelta, Utc};
+use jsonwebtoken::{EncodingKey, Header};
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+use super::{Checks, Decoders, config::AuthConfig};
+
+/// Mints a token with each secret like the identity provider would, with the configured
+/// audience, issuer and required claims, and decodes it like the token of a request.
+pub async fn run(
+    config: &AuthConfig,
+    decoders: &Decoders,
+    secrets: &[Vec<u8>],
+) -> anyhow::Result<()> {
+    let user_id = Uuid::new_v4();
+    let now = Utc::now();
+    let mut claims = json!({
+        "user_id": user_id,
+        "sub": user_id.to_string(),
+        "iat": now.timestamp(),
+        "nbf": now.timestamp(),
+        "exp": (now + TimeDelta::minutes(1)).timestamp(),
+    });
+    if let Value::Object(claims) = &mut claims {
+        if let Some(aud) = config.audience().and_then(|aud| aud.into_iter().next()) {
+            claims.insert("aud".into(), aud.into());
+        }
+        if let Some(iss) = config.jwt_iss.iter().flatten().next() {
+            claims.insert("iss".into(), iss.clone().into());
+        }
+        for claim in config.required_claims() {
+            claims.entry(claim).or_insert_with(|| json!([]));
+        }
+    }
+    for (index, secret) in secrets.iter().enumerate() {
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret),
+        )?;
+        let decoded = decoders
+            .decode(config, &token, Checks::default())
+            .await
+            .with_context(|| format!("token signed with jwt key secret {} refused", index + 1))?;
+        anyhow::ensure!(
+            decoded.claims.user_id == user_id,
+            "token signed with jwt key secret {} decoded to another user",
+            index + 1
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use rocket::{State, http::Status, serde::json::Json};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{body::JsonBody, model::read::AdminUser};
+
+use super::{Checks, Decoders, check_token_length, config::AuthConfig, revocation::RevokedTokens};
+
+const MAX_BATCH: usize = 100;
+
+#[derive(Debug, Serialize)]
+pub struct Verification {
+    pub valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Validates up to [`MAX_BATCH`] tokens in one call, for gateways that cache the results.
+#[post("/auth/verify-batch", data = "<tokens>")]
+pub async fn verify_batch(
+    _admin: AdminUser,
+    config: &State<AuthConfig>,
+    decoders: &State<Decoders>,
+    revoked: &State<RevokedTokens>,
+    tokens: JsonBody<Vec<String>>,
+) -> Result<Json<Vec<Verification>>, Status> {
+    if tokens.len() > MAX_BATCH {
+        return Err(Status::PayloadTooLarge);
+    }
+    let mut verifications = Vec::with_capacity(tokens.len());
+    for token in tokens.iter() {
+        let verified = match check_token_length(config, token) {
+            Ok(()) => decoders
+                .decode(config, token, Checks::default())
+                .await
+                .and_then(|decoded| revoked.check(decoded.claims)),
+            Err(err) => Err(err),
+        };
+        let verification = match verified {
+            Ok(token) => Verification {
+                valid: true,
+                user_id: Some(token.user_id()),
+                error: None,
+            },
+            Err(err) => Verification {
+                valid: false,
+                user_id: None,
+                error: Some(err.to_string()),
+            },
+        };
+        verifications.push(verification);
+    }
+    Ok(Json(verifications))
+}
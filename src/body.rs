@@ -0,0 +1,78 @@
+use std::ops::Deref;
+
+use rocket::{
+    Data, Request,
+    data::{self, FromData},
+    http::Status,
+    outcome::Outcome,
+    serde::json::{self, Json},
+};
+use serde::de::DeserializeOwned;
+
+use crate::authentication::error::ApiError;
+
+/// A JSON request body like [`Json`], whose errors are answered by the catchers with the serde
+/// detail, like which field is missing, instead of rocket's default page.
+#[derive(Debug)]
+pub struct JsonBody<T>(pub T);
+
+impl<T> JsonBody<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for JsonBody<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// Why the body of the request could not be read, for the catchers.
+#[derive(Default)]
+struct BodyError(Option<String>);
+
+#[async_trait]
+impl<'r, T: DeserializeOwned> FromData<'r> for JsonBody<T> {
+    type Error = json::Error<'r>;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        match Json::<T>::from_data(request, data).await {
+            Outcome::Success(Json(value)) => Outcome::Success(JsonBody(value)),
+            Outcome::Error((status, err)) => {
+                let message = match &err {
+                    json::Error::Io(err) => err.to_string(),
+                    json::Error::Parse(_, err) => err.to_string(),
+                };
+                request.local_cache(|| BodyError(Some(message)));
+                Outcome::Error((status, err))
+            }
+            Outcome::Forward(forward) => Outcome::Forward(forward),
+        }
+    }
+}
+
+fn invalid_request(status: Status, request: &Request) -> ApiError {
+    let message = request
+        .local_cache(BodyError::default)
+        .0
+        .clone()
+        .unwrap_or_else(|| status.reason_lossy().to_lowercase());
+    ApiError {
+        status,
+        error: "invalid_request",
+        message,
+    }
+}
+
+#[catch(400)]
+pub fn bad_request(request: &Request) -> ApiError {
+    invalid_request(Status::BadRequest, request)
+}
+
+#[catch(422)]
+pub fn unprocessable_entity(request: &Request) -> ApiError {
+    invalid_request(Status::UnprocessableEntity, request)
+}
@@ -0,0 +1,84 @@
+use std::{env, io::Cursor};
+
+use rocket::{
+    Request, Response,
+    fairing::{Fairing, Info, Kind},
+    http::{Header, Method, Status},
+};
+
+/// Adds the CORS headers for the origins in `CORS_ALLOWED_ORIGINS`, and answers preflights.
+///
+/// The listed origins may send credentials, like the auth cookie. With `*`, any other origin may
+/// read the responses, but never with credentials.
+pub struct Cors {
+    allowed_origins: Vec<String>,
+}
+
+pub fn fairing() -> Cors {
+    Cors::new(
+        env::var("CORS_ALLOWED_ORIGINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty()),
+    )
+}
+
+impl Cors {
+    pub fn new(allowed_origins: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Cors {
+            allowed_origins: allowed_origins.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.allowed_origins.iter().any(|allowed| allowed == origin)
+    }
+
+    fn allows_any(&self) -> bool {
+        self.allowed_origins.iter().any(|allowed| allowed == "*")
+    }
+}
+
+#[async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Some(origin) = request.headers().get_one("Origin") else {
+            return;
+        };
+        if self.allows(origin) {
+            // Credentials are allowed, so the origin has to be echoed rather than `*`.
+            response.set_header(Header::new(
+                "Access-Control-Allow-Origin",
+                origin.to_string(),
+            ));
+            response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+            response.set_header(Header::new("Vary", "Origin"));
+        } else if self.allows_any() {
+            response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+        } else {
+            return;
+        }
+
+        if request.method() == Method::Options {
+            response.set_header(Header::new(
+                "Access-Control-Allow-Methods",
+                "GET, POST, PATCH, DELETE, OPTIONS",
+            ));
+            response.set_header(Header::new(
+                "Access-Control-Allow-Headers",
+                "Authorization, Content-Type, Idempotency-Key, If-None-Match, X-Request-Id",
+            ));
+            response.set_status(Status::NoContent);
+            response.remove_header("Content-Type");
+            response.set_sized_body(0, Cursor::new(""));
+        }
+    }
+}
@@ -0,0 +1,17 @@
+use std::env;
+
+use tracing_subscriber::fmt;
+
+/// Installs the subscriber for the structured events, as JSON when `LOG_FORMAT=json` and
+/// human readable otherwise. Rocket keeps logging its own messages as before.
+pub fn init() {
+    let json = env::var("LOG_FORMAT").is_ok_and(|format| format.eq_ignore_ascii_case("json"));
+    let result = if json {
+        fmt().json().with_current_span(false).try_init()
+    } else {
+        fmt().try_init()
+    };
+    if let Err(err) = result {
+        eprintln!("Failed to install the log subscriber: {err}");
+    }
+}
@@ -3,18 +3,80 @@ use rocket::{Build, Rocket};
 #[macro_use]
 extern crate rocket;
 
+mod audit;
 mod authentication;
+mod body;
+mod cors;
+mod logging;
 mod model;
+mod monitoring;
+mod repository;
+mod request_id;
+mod shutdown;
+#[cfg(test)]
+mod tests;
+mod users;
 
-#[launch]
-async fn rocket() -> _ {
-    let rocket = rocket::build();
+#[rocket::main]
+async fn main() -> Result<(), Box<rocket::Error>> {
+    logging::init();
+    let rocket = rocket::custom(shutdown::figment());
 
-    mount(rocket).await
+    mount(rocket).await.launch().await.map_err(Box::new)?;
+    tracing::info!("Shutdown complete");
+    Ok(())
 }
 
 async fn mount(rocket: Rocket<Build>) -> Rocket<Build> {
     rocket
-        .mount("/", routes![authentication::get_me])
+        .mount(
+            "/",
+            routes![
+                authentication::get_me,
+                authentication::get_my_permissions,
+                authentication::introspect,
+                authentication::debug::debug_token,
+                authentication::verify::verify_batch,
+                authentication::health::get_health,
+                authentication::health::get_ready,
+                authentication::jwks::get_jwks,
+                authentication::jwks::get_loaded_keys,
+                authentication::jwks::refresh_keys,
+                authentication::revocation::revoke,
+                authentication::revocation::logout,
+                monitoring::get_metrics,
+                users::get_my_profile,
+                users::list_users,
+                users::search_users,
+                users::get_user,
+                users::create_user,
+                users::update_user,
+                users::delete_user,
+                users::grant_environment,
+                users::bulk_grant_environment,
+                users::revoke_environment,
+            ],
+        )
+        .register(
+            "/",
+            catchers![
+                authentication::error::unauthorized,
+                authentication::error::forbidden,
+                authentication::error::not_found,
+                authentication::error::too_many_requests,
+                body::bad_request,
+                body::unprocessable_entity
+            ],
+        )
         .attach(authentication::fairing())
+        .attach(authentication::public::fairing())
+        .attach(audit::fairing())
+        .attach(authentication::check_keys())
+        .attach(authentication::timing())
+        .attach(cors::fairing())
+        .attach(monitoring::fairing())
+        .attach(request_id::fairing())
+        .attach(shutdown::fairing())
+        .attach(repository::fairing())
+        .attach(users::idempotency::fairing())
 }
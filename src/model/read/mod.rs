@@ -5,17 +5,49 @@ use thiserror::Error;
 
 pub mod platform {
     use super::*;
-    use serde::Serialize;
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
     use std::collections::HashSet;
     use uuid::Uuid;
 
-    #[derive(Debug, Serialize)]
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct User {
         pub id: Uuid,
         pub email: String,
         pub given_name: String,
         pub family_name: String,
+        #[serde(default)]
         pub environments: HashSet<Environment>,
+        /// Tokens issued before this are no longer accepted, to end all sessions of the user.
+        pub sessions_valid_after: Option<DateTime<Utc>>,
+        #[serde(default = "Utc::now")]
+        pub created_at: DateTime<Utc>,
+        /// When the account was deactivated, it is kept for audits but can no longer be used.
+        pub deleted_at: Option<DateTime<Utc>>,
+    }
+
+    impl User {
+        /// A new user with a freshly generated id.
+        pub fn new(info: UserInfo, environments: HashSet<Environment>) -> Self {
+            Self {
+                id: Uuid::new_v4(),
+                email: info.email.to_string(),
+                given_name: info.given_name,
+                family_name: info.family_name,
+                environments,
+                sessions_valid_after: None,
+                created_at: Utc::now(),
+                deleted_at: None,
+            }
+        }
+
+        /// Orders users like [`UserInfo`]: by family name, then given name, then email.
+        pub fn cmp_by_name(&self, other: &Self) -> Ordering {
+            cmp_names(
+                (&self.family_name, &self.given_name, &self.email),
+                (&other.family_name, &other.given_name, &other.email),
+            )
+        }
     }
 }
 
@@ -26,12 +58,34 @@ pub struct UserInfo {
     pub family_name: String,
 }
 
+#[derive(Debug, Clone, Error, Eq, PartialEq)]
+#[error("'{email}' is not a valid email address: {reason}")]
+pub struct InvalidEmail {
+    pub email: String,
+    pub reason: String,
+}
+
+/// Validates an email address taken from a request.
+pub fn parse_email(email: &str) -> Result<EmailAddress, InvalidEmail> {
+    EmailAddress::from_str(email.trim()).map_err(|err| InvalidEmail {
+        email: email.to_string(),
+        reason: err.to_string(),
+    })
+}
+
+/// The order of users, as `(family_name, given_name, email)`.
+fn cmp_names(a: (&str, &str, &str), b: (&str, &str, &str)) -> Ordering {
+    a.0.cmp(b.0)
+        .then_with(|| a.1.cmp(b.1))
+        .then_with(|| a.2.cmp(b.2))
+}
+
 impl Ord for UserInfo {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.family_name
-            .cmp(&other.family_name)
-            .then_with(|| self.given_name.cmp(&other.given_name))
-            .then_with(|| self.email.as_str().cmp(other.email.as_str()))
+        cmp_names(
+            (&self.family_name, &self.given_name, self.email.as_str()),
+            (&other.family_name, &other.given_name, other.email.as_str()),
+        )
     }
 }
 
@@ -45,15 +99,44 @@ impl PartialOrd for UserInfo {
 #[serde(rename_all = "lowercase")]
 pub enum Environment {
     Prod,
+    Staging,
     Test,
 }
 
-use serde::{Deserialize, Serialize};
+impl Environment {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Environment::Prod => "prod",
+            Environment::Staging => "staging",
+            Environment::Test => "test",
+        }
+    }
+}
+
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Environment {
+    type Err = serde::de::value::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::deserialize(s.into_deserializer())
+    }
+}
+
+use serde::{Deserialize, Serialize, de::IntoDeserializer};
 use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
 
 /// The authenticated admin user making the request.
 #[derive(Debug, Copy, Clone)]
-pub struct AdminUser;
+pub struct AdminUser {
+    pub id: Uuid,
+}
 
 /// The authenticated user making the request.
 #[derive(Debug, Clone)]
@@ -61,11 +144,6 @@ pub struct AuthorizedUser {
     pub id: Uuid,
 }
 
-impl AuthorizedUser {
-    pub fn create(id: Uuid) -> anyhow::Result<Self> {
-        Ok(Self { id })
-    }
-}
 #[derive(Debug, Clone, Error, Eq, PartialEq)]
 #[error("You do not have sufficient permissions to perform this action")]
 pub struct Forbidden;
@@ -78,6 +156,8 @@ pub struct UserDoesNotExist;
 pub enum GrantAccessError {
     #[error("You do not have sufficient permissions to perform this action")]
     Forbidden,
+    #[error("User not found")]
+    UserDoesNotExist,
 }
 
 impl From<Forbidden> for GrantAccessError {
@@ -85,3 +165,9 @@ impl From<Forbidden> for GrantAccessError {
         Self::Forbidden
     }
 }
+
+impl From<UserDoesNotExist> for GrantAccessError {
+    fn from(_: UserDoesNotExist) -> Self {
+        Self::UserDoesNotExist
+    }
+}
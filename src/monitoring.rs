@@ -0,0 +1,35 @@
+use std::sync::OnceLock;
+
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use rocket::{
+    fairing::{AdHoc, Fairing},
+    http::ContentType,
+};
+use tracing::error;
+
+static PROMETHEUS: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Installs the global metrics recorder, once for all rockets of the process.
+pub fn fairing() -> impl Fairing {
+    AdHoc::on_ignite("Install metrics recorder", |rocket| async {
+        PROMETHEUS.get_or_init(|| {
+            let recorder = PrometheusBuilder::new().build_recorder();
+            let handle = recorder.handle();
+            if let Err(err) = metrics::set_global_recorder(recorder) {
+                error!(error = %err, "Failed to install metrics recorder");
+            }
+            handle
+        });
+        rocket
+    })
+}
+
+/// The metrics in the Prometheus text format.
+#[get("/metrics")]
+pub fn get_metrics() -> (ContentType, String) {
+    let metrics = PROMETHEUS
+        .get()
+        .map(PrometheusHandle::render)
+        .unwrap_or_default();
+    (ContentType::Plain, metrics)
+}
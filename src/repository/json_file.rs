@@ -0,0 +1,140 @@
+use std::{env, fs, io};
+
+use anyhow::Context;
+use uuid::Uuid;
+
+use super::{RepoError, UserChanges, UserRepository, memory::InMemoryUserRepository};
+use crate::model::read::{Environment, platform::User};
+
+/// Keeps the users of a JSON fixture in memory, for deterministic users in local development.
+///
+/// Changes are only written back to the file with `USERS_FIXTURE_PERSIST=true`.
+pub struct JsonFileUserRepository {
+    users: InMemoryUserRepository,
+    path: String,
+    persist: bool,
+}
+
+impl JsonFileUserRepository {
+    /// Reads the list of users in the file at `path`.
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("could not read USERS_FIXTURE_FILE '{path}'"))?;
+        let users: Vec<User> = serde_json::from_str(&json)
+            .with_context(|| format!("invalid users in USERS_FIXTURE_FILE '{path}'"))?;
+        Ok(Self {
+            users: InMemoryUserRepository::with_users(users),
+            path: path.to_string(),
+            persist: env::var("USERS_FIXTURE_PERSIST").is_ok_and(|v| v == "true" || v == "1"),
+        })
+    }
+
+    /// Writes the users back to the file after a change, if enabled.
+    async fn save<T>(&self, result: T) -> Result<T, RepoError> {
+        if !self.persist {
+            return Ok(result);
+        }
+        let json = serde_json::to_string_pretty(&self.users.all_users().await)
+            .map_err(io::Error::other)?;
+        rocket::tokio::fs::write(&self.path, json).await?;
+        Ok(result)
+    }
+}
+
+#[async_trait]
+impl UserRepository for JsonFileUserRepository {
+    async fn find_user(&self, id: Uuid) -> Result<Option<User>, RepoError> {
+        self.users.find_user(id).await
+    }
+
+    async fn find_user_with_deleted(&self, id: Uuid) -> Result<Option<User>, RepoError> {
+        self.users.find_user_with_deleted(id).await
+    }
+
+    async fn list_users(
+        &self,
+        limit: usize,
+        offset: usize,
+        environment: Option<Environment>,
+    ) -> Result<Vec<User>, RepoError> {
+        self.users.list_users(limit, offset, environment).await
+    }
+
+    async fn search_users(&self, prefix: &str, limit: usize) -> Result<Vec<User>, RepoError> {
+        self.users.search_users(prefix, limit).await
+    }
+
+    async fn create_user(&self, user: User) -> Result<User, RepoError> {
+        let user = self.users.create_user(user).await?;
+        self.save(user).await
+    }
+
+    async fn update_user(&self, id: Uuid, changes: UserChanges) -> Result<Option<User>, RepoError> {
+        let user = self.users.update_user(id, changes).await?;
+        self.save(user).await
+    }
+
+    async fn delete_user(&self, id: Uuid) -> Result<Option<User>, RepoError> {
+        let user = self.users.delete_user(id).await?;
+        self.save(user).await
+    }
+
+    async fn grant_environment(
+        &self,
+        id: Uuid,
+        environment: Environment,
+    ) -> Result<Option<User>, RepoError> {
+        let user = self.users.grant_environment(id, environment).await?;
+        self.save(user).await
+    }
+
+    async fn revoke_environment(
+        &self,
+        id: Uuid,
+        environment: Environment,
+    ) -> Result<Option<User>, RepoError> {
+        let user = self.users.revoke_environment(id, environment).await?;
+        self.save(user).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rocket::async_test]
+    async fn fixture_users_are_loaded_and_changes_persisted() {
+        let path = env::temp_dir().join(format!("users-{}.json", Uuid::new_v4()));
+        let id = Uuid::new_v4();
+        let fixture = serde_json::json!([{
+            "id": id,
+            "email": "ada@example.com",
+            "given_name": "Ada",
+            "family_name": "Lovelace",
+        }]);
+        fs::write(&path, fixture.to_string()).unwrap();
+        let mut repository = JsonFileUserRepository::load(path.to_str().unwrap()).unwrap();
+        repository.persist = true;
+
+        let user = repository
+            .find_user(id)
+            .await
+            .unwrap()
+            .expect("fixture user");
+        assert_eq!(user.email, "ada@example.com");
+        assert!(user.environments.is_empty());
+
+        repository
+            .grant_environment(id, Environment::Staging)
+            .await
+            .unwrap();
+        let reloaded = JsonFileUserRepository::load(path.to_str().unwrap()).unwrap();
+        let user = reloaded
+            .find_user(id)
+            .await
+            .unwrap()
+            .expect("persisted user");
+        assert!(user.environments.contains(&Environment::Staging));
+        fs::remove_file(path).unwrap();
+    }
+}
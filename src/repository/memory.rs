@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use rocket::tokio::sync::RwLock;
+use uuid::Uuid;
+
+use super::{RepoError, UserChanges, UserRepository};
+use crate::model::read::{Environment, platform::User};
+
+/// Keeps the users in memory only, for tests and local development.
+#[derive(Debug, Default)]
+pub struct InMemoryUserRepository {
+    users: RwLock<HashMap<Uuid, User>>,
+}
+
+impl InMemoryUserRepository {
+    pub fn with_users(users: impl IntoIterator<Item = User>) -> Self {
+        Self {
+            users: RwLock::new(users.into_iter().map(|user| (user.id, user)).collect()),
+        }
+    }
+
+    /// All users, deleted ones included, ordered by name.
+    pub async fn all_users(&self) -> Vec<User> {
+        let mut users: Vec<User> = self.users.read().await.values().cloned().collect();
+        users.sort_by(User::cmp_by_name);
+        users
+    }
+}
+
+fn is_active(user: &User) -> bool {
+    user.deleted_at.is_none()
+}
+
+/// Whether the emails are the same ignoring case, like the unique index of the postgres table.
+fn same_email(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+#[async_trait]
+impl UserRepository for InMemoryUserRepository {
+    async fn find_user(&self, id: Uuid) -> Result<Option<User>, RepoError> {
+        let users = self.users.read().await;
+        Ok(users.get(&id).filter(|user| is_active(user)).cloned())
+    }
+
+    async fn find_user_with_deleted(&self, id: Uuid) -> Result<Option<User>, RepoError> {
+        Ok(self.users.read().await.get(&id).cloned())
+    }
+
+    async fn list_users(
+        &self,
+        limit: usize,
+        offset: usize,
+        environment: Option<Environment>,
+    ) -> Result<Vec<User>, RepoError> {
+        let mut users: Vec<User> = self
+            .users
+            .read()
+            .await
+            .values()
+            .filter(|user| is_active(user))
+            .filter(|user| environment.is_none_or(|env| user.environments.contains(&env)))
+            .cloned()
+            .collect();
+        users.sort_by(User::cmp_by_name);
+        Ok(users.into_iter().skip(offset).take(limit).collect())
+    }
+
+    async fn search_users(&self, prefix: &str, limit: usize) -> Result<Vec<User>, RepoError> {
+        let prefix = prefix.to_lowercase();
+        let mut users: Vec<User> = self
+            .users
+            .read()
+            .await
+            .values()
+            .filter(|user| is_active(user) && user.email.to_lowercase().starts_with(&prefix))
+            .cloned()
+            .collect();
+        users.sort_by(User::cmp_by_name);
+        users.truncate(limit);
+        Ok(users)
+    }
+
+    async fn create_user(&self, user: User) -> Result<User, RepoError> {
+        let mut users = self.users.write().await;
+        if users
+            .values()
+            .any(|existing| same_email(&existing.email, &user.email))
+        {
+            return Err(RepoError::DuplicateEmail);
+        }
+        users.insert(user.id, user.clone());
+        Ok(user)
+    }
+
+    async fn update_user(&self, id: Uuid, changes: UserChanges) -> Result<Option<User>, RepoError> {
+        let mut users = self.users.write().await;
+        if let Some(email) = &changes.email
+            && users
+                .values()
+                .any(|existing| existing.id != id && same_email(&existing.email, email))
+        {
+            return Err(RepoError::DuplicateEmail);
+        }
+        Ok(users
+            .get_mut(&id)
+            .filter(|user| is_active(user))
+            .map(|user| {
+                if let Some(email) = changes.email {
+                    user.email = email;
+                }
+                if let Some(given_name) = changes.given_name {
+                    user.given_name = given_name;
+                }
+                if let Some(family_name) = changes.family_name {
+                    user.family_name = family_name;
+                }
+                user.clone()
+            }))
+    }
+
+    async fn delete_user(&self, id: Uuid) -> Result<Option<User>, RepoError> {
+        let mut users = self.users.write().await;
+        Ok(users.get_mut(&id).map(|user| {
+            user.deleted_at.get_or_insert_with(Utc::now);
+            user.clone()
+        }))
+    }
+
+    async fn grant_environment(
+        &self,
+        id: Uuid,
+        environment: Environment,
+    ) -> Result<Option<User>, RepoError> {
+        let mut users = self.users.write().await;
+        Ok(users
+            .get_mut(&id)
+            .filter(|user| is_active(user))
+            .map(|user| {
+                user.environments.insert(environment);
+                user.clone()
+            }))
+    }
+
+    async fn revoke_environment(
+        &self,
+        id: Uuid,
+        environment: Environment,
+    ) -> Result<Option<User>, RepoError> {
+        let mut users = self.users.write().await;
+        Ok(users
+            .get_mut(&id)
+            .filter(|user| is_active(user))
+            .map(|user| {
+                user.environments.remove(&environment);
+                user.clone()
+            }))
+    }
+}
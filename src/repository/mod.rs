@@ -0,0 +1,124 @@
+use std::env;
+
+use rocket::fairing::{AdHoc, Fairing};
+use thiserror::Error;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::model::read::{Environment, platform::User};
+use json_file::JsonFileUserRepository;
+use memory::InMemoryUserRepository;
+use postgres::PgUserRepository;
+
+pub mod json_file;
+pub mod memory;
+pub mod postgres;
+
+#[derive(Debug, Error)]
+pub enum RepoError {
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("a user with this email already exists")]
+    DuplicateEmail,
+    #[error("could not write the users fixture: {0}")]
+    Fixture(#[from] std::io::Error),
+}
+
+/// The details of a user to change, fields left `None` are kept.
+#[derive(Debug, Default)]
+pub struct UserChanges {
+    pub email: Option<String>,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+}
+
+/// Where the platform users are stored.
+///
+/// Deleted users are only returned by [`UserRepository::find_user_with_deleted`] and
+/// [`UserRepository::delete_user`], all other methods treat them as if they did not exist.
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    /// The user, unless it does not exist or was deleted.
+    async fn find_user(&self, id: Uuid) -> Result<Option<User>, RepoError>;
+
+    /// The user, also when it was deleted.
+    async fn find_user_with_deleted(&self, id: Uuid) -> Result<Option<User>, RepoError>;
+
+    /// A page of the users that are not deleted ordered by name, optionally only those with
+    /// access to `environment`.
+    async fn list_users(
+        &self,
+        limit: usize,
+        offset: usize,
+        environment: Option<Environment>,
+    ) -> Result<Vec<User>, RepoError>;
+
+    /// At most `limit` users that are not deleted whose email starts with `prefix`, ignoring
+    /// case, ordered by name.
+    async fn search_users(&self, prefix: &str, limit: usize) -> Result<Vec<User>, RepoError>;
+
+    /// Stores a new user, failing with [`RepoError::DuplicateEmail`] if the email is taken,
+    /// ignoring case.
+    async fn create_user(&self, user: User) -> Result<User, RepoError>;
+
+    /// Applies the given changes to the user, returning the updated user if it exists. Fails with
+    /// [`RepoError::DuplicateEmail`] if the new email belongs to another user, ignoring case.
+    async fn update_user(&self, id: Uuid, changes: UserChanges) -> Result<Option<User>, RepoError>;
+
+    /// Marks the user as deleted, keeping its record. Returns the user if it exists, deleted
+    /// before or not.
+    async fn delete_user(&self, id: Uuid) -> Result<Option<User>, RepoError>;
+
+    /// Gives the user access to `environment`, returning the updated user if it exists.
+    async fn grant_environment(
+        &self,
+        id: Uuid,
+        environment: Environment,
+    ) -> Result<Option<User>, RepoError>;
+
+    /// Takes access to `environment` away from the user, returning the updated user if it exists.
+    async fn revoke_environment(
+        &self,
+        id: Uuid,
+        environment: Environment,
+    ) -> Result<Option<User>, RepoError>;
+}
+
+/// The user repository managed by rocket.
+pub type Users = Box<dyn UserRepository>;
+
+/// Loads the users from the fixture at `USERS_FIXTURE_FILE`, or else connects to the database at
+/// `DATABASE_URL`, or else keeps the users in memory.
+///
+/// A repository already given to the rocket is kept.
+pub fn fairing() -> impl Fairing {
+    AdHoc::try_on_ignite("Connect user repository", |rocket| async {
+        if rocket.state::<Users>().is_some() {
+            return Ok(rocket);
+        }
+        if let Ok(path) = env::var("USERS_FIXTURE_FILE") {
+            return match JsonFileUserRepository::load(&path) {
+                Ok(repository) => Ok(rocket.manage::<Users>(Box::new(repository))),
+                Err(err) => {
+                    error!(
+                        path,
+                        error = format!("{err:#}"),
+                        "Failed to load the users fixture"
+                    );
+                    Err(rocket)
+                }
+            };
+        }
+        let Ok(url) = env::var("DATABASE_URL") else {
+            warn!("DATABASE_URL is not set, keeping users in memory");
+            return Ok(rocket.manage::<Users>(Box::new(InMemoryUserRepository::default())));
+        };
+        match PgUserRepository::connect(&url).await {
+            Ok(repository) => Ok(rocket.manage::<Users>(Box::new(repository))),
+            Err(err) => {
+                error!(error = %err, "Failed to connect to the user database");
+                Err(rocket)
+            }
+        }
+    })
+}
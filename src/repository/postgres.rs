@@ -0,0 +1,196 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::{RepoError, UserChanges, UserRepository};
+use crate::model::read::{Environment, platform::User};
+
+/// Stores the users in the `users` table of a postgres database.
+pub struct PgUserRepository {
+    pool: PgPool,
+}
+
+impl PgUserRepository {
+    pub async fn connect(url: &str) -> Result<Self, RepoError> {
+        let pool = PgPool::connect(url).await?;
+        sqlx::migrate!()
+            .run(&pool)
+            .await
+            .map_err(sqlx::Error::from)?;
+        Ok(Self { pool })
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct UserRow {
+    id: Uuid,
+    email: String,
+    given_name: String,
+    family_name: String,
+    environments: Vec<String>,
+    sessions_valid_after: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+impl From<UserRow> for User {
+    fn from(row: UserRow) -> Self {
+        Self {
+            id: row.id,
+            email: row.email,
+            given_name: row.given_name,
+            family_name: row.family_name,
+            environments: row
+                .environments
+                .iter()
+                .filter_map(|env| env.parse().ok())
+                .collect(),
+            sessions_valid_after: row.sessions_valid_after,
+            created_at: row.created_at,
+            deleted_at: row.deleted_at,
+        }
+    }
+}
+
+#[async_trait]
+impl UserRepository for PgUserRepository {
+    async fn find_user(&self, id: Uuid) -> Result<Option<User>, RepoError> {
+        let row: Option<UserRow> = sqlx::query_as(
+            "SELECT id, email, given_name, family_name, environments, sessions_valid_after, created_at, \
+             deleted_at FROM users WHERE id = $1 AND deleted_at IS NULL",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(User::from))
+    }
+
+    async fn find_user_with_deleted(&self, id: Uuid) -> Result<Option<User>, RepoError> {
+        let row: Option<UserRow> = sqlx::query_as(
+            "SELECT id, email, given_name, family_name, environments, sessions_valid_after, created_at, \
+             deleted_at FROM users WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(User::from))
+    }
+
+    async fn list_users(
+        &self,
+        limit: usize,
+        offset: usize,
+        environment: Option<Environment>,
+    ) -> Result<Vec<User>, RepoError> {
+        let rows: Vec<UserRow> = sqlx::query_as(
+            "SELECT id, email, given_name, family_name, environments, sessions_valid_after, created_at, \
+             deleted_at FROM users \
+             WHERE deleted_at IS NULL AND ($3::TEXT IS NULL OR $3 = ANY(environments)) \
+             ORDER BY family_name, given_name, email LIMIT $1 OFFSET $2",
+        )
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .bind(environment.map(|env| env.as_str()))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(User::from).collect())
+    }
+
+    async fn search_users(&self, prefix: &str, limit: usize) -> Result<Vec<User>, RepoError> {
+        // The wildcards of the prefix itself are escaped, so that it only matches literally.
+        let pattern = prefix
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        let rows: Vec<UserRow> = sqlx::query_as(
+            "SELECT id, email, given_name, family_name, environments, sessions_valid_after, created_at, \
+             deleted_at FROM users WHERE deleted_at IS NULL AND email ILIKE $1 || '%' \
+             ORDER BY family_name, given_name, email LIMIT $2",
+        )
+        .bind(pattern)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(User::from).collect())
+    }
+
+    async fn create_user(&self, user: User) -> Result<User, RepoError> {
+        let environments: Vec<&str> = user.environments.iter().map(|env| env.as_str()).collect();
+        sqlx::query(
+            "INSERT INTO users (id, email, given_name, family_name, environments, sessions_valid_after, created_at) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        )
+        .bind(user.id)
+        .bind(&user.email)
+        .bind(&user.given_name)
+        .bind(&user.family_name)
+        .bind(environments)
+        .bind(user.sessions_valid_after)
+        .bind(user.created_at)
+        .execute(&self.pool)
+        .await
+        .map_err(|err| match err {
+            sqlx::Error::Database(err) if err.is_unique_violation() => RepoError::DuplicateEmail,
+            err => RepoError::Database(err),
+        })?;
+        Ok(user)
+    }
+
+    async fn update_user(&self, id: Uuid, changes: UserChanges) -> Result<Option<User>, RepoError> {
+        sqlx::query(
+            "UPDATE users SET email = COALESCE($2, email), given_name = COALESCE($3, given_name), \
+             family_name = COALESCE($4, family_name) WHERE id = $1 AND deleted_at IS NULL",
+        )
+        .bind(id)
+        .bind(changes.email)
+        .bind(changes.given_name)
+        .bind(changes.family_name)
+        .execute(&self.pool)
+        .await
+        .map_err(|err| match err {
+            sqlx::Error::Database(err) if err.is_unique_violation() => RepoError::DuplicateEmail,
+            err => RepoError::Database(err),
+        })?;
+        self.find_user(id).await
+    }
+
+    async fn delete_user(&self, id: Uuid) -> Result<Option<User>, RepoError> {
+        sqlx::query("UPDATE users SET deleted_at = COALESCE(deleted_at, now()) WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        self.find_user_with_deleted(id).await
+    }
+
+    async fn grant_environment(
+        &self,
+        id: Uuid,
+        environment: Environment,
+    ) -> Result<Option<User>, RepoError> {
+        sqlx::query(
+            "UPDATE users SET environments = array_append(environments, $2) \
+             WHERE id = $1 AND deleted_at IS NULL AND NOT ($2 = ANY(environments))",
+        )
+        .bind(id)
+        .bind(environment.as_str())
+        .execute(&self.pool)
+        .await?;
+        self.find_user(id).await
+    }
+
+    async fn revoke_environment(
+        &self,
+        id: Uuid,
+        environment: Environment,
+    ) -> Result<Option<User>, RepoError> {
+        sqlx::query(
+            "UPDATE users SET environments = array_remove(environments, $2) \
+             WHERE id = $1 AND deleted_at IS NULL",
+        )
+        .bind(id)
+        .bind(environment.as_str())
+        .execute(&self.pool)
+        .await?;
+        self.find_user(id).await
+    }
+}
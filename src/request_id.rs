@@ -0,0 +1,58 @@
+use std::fmt;
+
+use rocket::{
+    Data, Request, Response,
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+};
+use uuid::Uuid;
+
+const HEADER: &str = "X-Request-Id";
+
+/// Correlates the logs of a request, taken from its `X-Request-Id` header or generated.
+#[derive(Debug, Clone)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r RequestId {
+        request.local_cache(|| {
+            let id = request
+                .headers()
+                .get_one(HEADER)
+                .map(String::from)
+                .unwrap_or_else(|| Uuid::new_v4().to_string());
+            RequestId(id)
+        })
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+pub struct RequestIdFairing;
+
+pub fn fairing() -> RequestIdFairing {
+    RequestIdFairing
+}
+
+#[async_trait]
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request id",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        RequestId::of(request);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let id = RequestId::of(request).to_string();
+        response.set_header(Header::new(HEADER, id));
+    }
+}
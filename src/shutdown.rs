@@ -0,0 +1,33 @@
+use std::env;
+
+use rocket::{
+    fairing::{AdHoc, Fairing},
+    figment::Figment,
+};
+use tracing::info;
+
+const DEFAULT_GRACE_SECS: u32 = 30;
+
+/// The rocket config, giving in-flight requests up to `SHUTDOWN_GRACE_SECS` to finish once
+/// SIGTERM or ctrl-c is received, while no new connections are accepted.
+///
+/// A grace set in the rocket config itself, like `ROCKET_SHUTDOWN`, is kept.
+pub fn figment() -> Figment {
+    let grace = env::var("SHUTDOWN_GRACE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_GRACE_SECS);
+    rocket::Config::figment().join(("shutdown.grace", grace))
+}
+
+/// Logs when the shutdown starts, completion is logged once the launch returns.
+pub fn fairing() -> impl Fairing {
+    AdHoc::on_shutdown("Log shutdown", |rocket| {
+        Box::pin(async move {
+            info!(
+                grace_secs = rocket.config().shutdown.grace,
+                "Shutting down, waiting for in-flight requests"
+            );
+        })
+    })
+}
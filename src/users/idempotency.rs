@@ -0,0 +1,77 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    env,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rocket::{
+    Request,
+    fairing::{AdHoc, Fairing},
+    request::{FromRequest, Outcome},
+};
+use uuid::Uuid;
+
+use crate::model::read::platform::User;
+
+const HEADER: &str = "Idempotency-Key";
+const DEFAULT_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// The `Idempotency-Key` header of a request, if it sent one.
+pub struct IdempotencyKey(pub Option<String>);
+
+#[async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = Infallible;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let key = request
+            .headers()
+            .get_one(HEADER)
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(String::from);
+        Outcome::Success(IdempotencyKey(key))
+    }
+}
+
+/// The users created with an idempotency key, by the admin and key that created them, so a
+/// retried request gets the same user for `IDEMPOTENCY_TTL_SECS` instead of a duplicate.
+pub struct IdempotencyKeys {
+    ttl: Duration,
+    created: Mutex<HashMap<(Uuid, String), (Instant, User)>>,
+}
+
+impl IdempotencyKeys {
+    pub fn from_env() -> Self {
+        let ttl = env::var("IDEMPOTENCY_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_TTL_SECS);
+        Self {
+            ttl: Duration::from_secs(ttl),
+            created: Mutex::default(),
+        }
+    }
+
+    /// The user `admin` already created with `key`, unless that was longer than the ttl ago.
+    pub fn get(&self, admin: Uuid, key: &str) -> Option<User> {
+        let created = self.created.lock().expect("idempotency keys lock poisoned");
+        created
+            .get(&(admin, key.to_string()))
+            .filter(|(at, _)| at.elapsed() < self.ttl)
+            .map(|(_, user)| user.clone())
+    }
+
+    pub fn insert(&self, admin: Uuid, key: String, user: User) {
+        let mut created = self.created.lock().expect("idempotency keys lock poisoned");
+        created.retain(|_, (at, _)| at.elapsed() < self.ttl);
+        created.insert((admin, key), (Instant::now(), user));
+    }
+}
+
+pub fn fairing() -> impl Fairing {
+    AdHoc::on_ignite("Idempotency keys", |rocket| async {
+        rocket.manage(IdempotencyKeys::from_env())
+    })
+}
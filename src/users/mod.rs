@@ -0,0 +1,395 @@
+use std::collections::HashSet;
+
+use chrono::Utc;
+use rocket::{
+    Request, State,
+    http::Status,
+    response::{self, Responder},
+    serde::json::Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use idempotency::{IdempotencyKey, IdempotencyKeys};
+
+use crate::{
+    audit::{Audit, AuditAction},
+    authentication::{AccessToken, error::ErrorBody},
+    body::JsonBody,
+    model::read::{
+        AdminUser, Environment, GrantAccessError, InvalidEmail, UserInfo, parse_email,
+        platform::User,
+    },
+    repository::{RepoError, UserChanges, Users},
+};
+
+pub mod idempotency;
+
+impl From<GrantAccessError> for Status {
+    fn from(value: GrantAccessError) -> Self {
+        match value {
+            GrantAccessError::Forbidden => Status::Forbidden,
+            GrantAccessError::UserDoesNotExist => Status::NotFound,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for InvalidEmail {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let body = ErrorBody {
+            error: "invalid_email",
+            message: self.to_string(),
+        };
+        (Status::UnprocessableEntity, Json(body)).respond_to(request)
+    }
+}
+
+/// Why a user could not be created or updated.
+#[derive(Debug, Responder)]
+pub enum UserWriteError {
+    InvalidEmail(InvalidEmail),
+    Status(Status),
+}
+
+impl From<InvalidEmail> for UserWriteError {
+    fn from(value: InvalidEmail) -> Self {
+        Self::InvalidEmail(value)
+    }
+}
+
+impl From<RepoError> for UserWriteError {
+    fn from(value: RepoError) -> Self {
+        match value {
+            RepoError::DuplicateEmail => Self::Status(Status::Conflict),
+            err => {
+                error!(error = %err, "Failed to store user");
+                Self::Status(Status::InternalServerError)
+            }
+        }
+    }
+}
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 200;
+const SEARCH_LIMIT: usize = 20;
+const MIN_SEARCH_LEN: usize = 2;
+
+/// The profile of the caller, falling back to the token content when it cannot be loaded.
+///
+/// Without any name the `preferred_username` of the token is shown as the given name.
+#[get("/users/me/profile")]
+pub async fn get_my_profile(token: AccessToken, users: &State<Users>) -> Json<User> {
+    match users.find_user(token.user_id()).await {
+        Ok(Some(mut user)) => {
+            if user.given_name.is_empty() && user.family_name.is_empty() {
+                user.given_name = token.preferred_username.unwrap_or_default();
+            }
+            return Json(user);
+        }
+        Ok(None) => {
+            warn!(
+                user_id = %token.user_id(),
+                "User profile not found, using token content"
+            )
+        }
+        Err(err) => {
+            warn!(
+                user_id = %token.user_id(),
+                error = %err,
+                "Failed to load user profile, using token content"
+            )
+        }
+    }
+    Json(User {
+        id: token.user_id(),
+        email: token
+            .email
+            .map(|email| email.to_string())
+            .unwrap_or_default(),
+        given_name: token.preferred_username.unwrap_or_default(),
+        family_name: String::new(),
+        environments: HashSet::new(),
+        sessions_valid_after: None,
+        created_at: Utc::now(),
+        deleted_at: None,
+    })
+}
+
+#[get("/users?<limit>&<offset>&<environment>")]
+pub async fn list_users(
+    _admin: AdminUser,
+    users: &State<Users>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+    environment: Option<&str>,
+) -> Result<Json<Vec<User>>, Status> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let offset = offset.unwrap_or(0);
+    let environment = environment
+        .map(str::parse::<Environment>)
+        .transpose()
+        .map_err(|_| Status::BadRequest)?;
+    match users.list_users(limit, offset, environment).await {
+        Ok(users) => Ok(Json(users)),
+        Err(err) => {
+            error!(environment = ?environment, error = %err, "Failed to list users");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// A malformed id is a 404 like an unknown one, rather than the 422 rocket would give.
+#[get("/users/<id>")]
+pub async fn get_user(
+    _admin: AdminUser,
+    users: &State<Users>,
+    id: Result<Uuid, uuid::Error>,
+) -> Result<Json<User>, Status> {
+    let id = id.map_err(|_| Status::NotFound)?;
+    match users.find_user(id).await {
+        Ok(Some(user)) => Ok(Json(user)),
+        Ok(None) => Err(Status::NotFound),
+        Err(err) => {
+            error!(user_id = %id, error = %err, "Failed to load user");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Deletes the user softly, its record is kept for audits. Idempotent like revoking access.
+#[delete("/users/<id>")]
+pub async fn delete_user(
+    admin: AdminUser,
+    users: &State<Users>,
+    audit: &State<Audit>,
+    id: Uuid,
+) -> Status {
+    match users.delete_user(id).await {
+        Ok(Some(_)) => {
+            audit.record(admin.id, AuditAction::DeleteUser, id);
+            Status::NoContent
+        }
+        Ok(None) => Status::NotFound,
+        Err(err) => {
+            error!(user_id = %id, error = %err, "Failed to delete user");
+            Status::InternalServerError
+        }
+    }
+}
+
+/// The first users whose email starts with `q`, ignoring case. Shorter queries than
+/// [`MIN_SEARCH_LEN`] are refused, as they would match nearly everyone.
+#[get("/users/search?<q>")]
+pub async fn search_users(
+    _admin: AdminUser,
+    users: &State<Users>,
+    q: Option<&str>,
+) -> Result<Json<Vec<User>>, Status> {
+    let q = q.unwrap_or_default().trim();
+    if q.chars().count() < MIN_SEARCH_LEN {
+        return Err(Status::BadRequest);
+    }
+    match users.search_users(q, SEARCH_LIMIT).await {
+        Ok(users) => Ok(Json(users)),
+        Err(err) => {
+            error!(error = %err, "Failed to search users");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GrantEnvironment {
+    pub environment: Environment,
+}
+
+#[post("/users/<id>/environments", data = "<grant>")]
+pub async fn grant_environment(
+    admin: AdminUser,
+    users: &State<Users>,
+    audit: &State<Audit>,
+    id: Uuid,
+    grant: JsonBody<GrantEnvironment>,
+) -> Result<Json<User>, Status> {
+    match users.grant_environment(id, grant.environment).await {
+        Ok(Some(user)) => {
+            audit.record(
+                admin.id,
+                AuditAction::GrantEnvironment(grant.environment),
+                id,
+            );
+            Ok(Json(user))
+        }
+        Ok(None) => Err(GrantAccessError::UserDoesNotExist.into()),
+        Err(err) => {
+            error!(
+                user_id = %id,
+                environment = %grant.environment,
+                error = %err,
+                "Failed to grant environment access"
+            );
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BulkGrantEnvironment {
+    pub user_ids: Vec<Uuid>,
+    pub environment: Environment,
+}
+
+/// Whether one user of a bulk grant was given access.
+#[derive(Debug, Serialize)]
+pub struct GrantResult {
+    pub user_id: Uuid,
+    pub granted: bool,
+    pub error: Option<String>,
+}
+
+/// Grants the environment to every user, reporting those that could not be granted it rather
+/// than failing the batch. Only a repository failure aborts it, with the earlier grants kept.
+#[post("/users/environments/bulk", data = "<grant>")]
+pub async fn bulk_grant_environment(
+    admin: AdminUser,
+    users: &State<Users>,
+    audit: &State<Audit>,
+    grant: JsonBody<BulkGrantEnvironment>,
+) -> Result<Json<Vec<GrantResult>>, Status> {
+    let mut results = Vec::with_capacity(grant.user_ids.len());
+    for &user_id in &grant.user_ids {
+        let result = match users.grant_environment(user_id, grant.environment).await {
+            Ok(Some(_)) => {
+                let action = AuditAction::GrantEnvironment(grant.environment);
+                audit.record(admin.id, action, user_id);
+                Ok(())
+            }
+            Ok(None) => Err(GrantAccessError::UserDoesNotExist),
+            Err(err) => {
+                error!(
+                    %user_id,
+                    environment = %grant.environment,
+                    error = %err,
+                    "Failed to grant environment access"
+                );
+                return Err(Status::InternalServerError);
+            }
+        };
+        results.push(GrantResult {
+            user_id,
+            granted: result.is_ok(),
+            error: result.err().map(|err| err.to_string()),
+        });
+    }
+    Ok(Json(results))
+}
+
+/// Idempotent, revoking an environment the user does not have access to still succeeds.
+#[delete("/users/<id>/environments/<environment>")]
+pub async fn revoke_environment(
+    admin: AdminUser,
+    users: &State<Users>,
+    audit: &State<Audit>,
+    id: Uuid,
+    environment: &str,
+) -> Status {
+    let Ok(environment) = environment.parse::<Environment>() else {
+        return Status::BadRequest;
+    };
+    match users.revoke_environment(id, environment).await {
+        Ok(Some(_)) => {
+            audit.record(admin.id, AuditAction::RevokeEnvironment(environment), id);
+            Status::NoContent
+        }
+        Ok(None) => GrantAccessError::UserDoesNotExist.into(),
+        Err(err) => {
+            error!(
+                user_id = %id,
+                %environment,
+                error = %err,
+                "Failed to revoke environment access"
+            );
+            Status::InternalServerError
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewUser {
+    pub email: String,
+    pub given_name: String,
+    pub family_name: String,
+    #[serde(default)]
+    pub environments: HashSet<Environment>,
+}
+
+/// Creates the user, or responds with the user created before when an admin retries with the
+/// same `Idempotency-Key`.
+#[post("/users", data = "<new_user>")]
+pub async fn create_user(
+    admin: AdminUser,
+    users: &State<Users>,
+    audit: &State<Audit>,
+    idempotency: &State<IdempotencyKeys>,
+    key: IdempotencyKey,
+    new_user: JsonBody<NewUser>,
+) -> Result<(Status, Json<User>), UserWriteError> {
+    if let Some(user) = key
+        .0
+        .as_deref()
+        .and_then(|key| idempotency.get(admin.id, key))
+    {
+        return Ok((Status::Created, Json(user)));
+    }
+    let new_user = new_user.into_inner();
+    let info = UserInfo {
+        email: parse_email(&new_user.email)?,
+        given_name: new_user.given_name.trim().to_string(),
+        family_name: new_user.family_name.trim().to_string(),
+    };
+    let user = users
+        .create_user(User::new(info, new_user.environments))
+        .await?;
+    audit.record(admin.id, AuditAction::CreateUser, user.id);
+    if let Some(key) = key.0 {
+        idempotency.insert(admin.id, key, user.clone());
+    }
+    Ok((Status::Created, Json(user)))
+}
+
+/// The details to change, fields left out are kept.
+#[derive(Debug, Deserialize)]
+pub struct UpdateUser {
+    pub email: Option<String>,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+}
+
+#[patch("/users/<id>", data = "<update>")]
+pub async fn update_user(
+    admin: AdminUser,
+    users: &State<Users>,
+    audit: &State<Audit>,
+    id: Uuid,
+    update: JsonBody<UpdateUser>,
+) -> Result<Json<User>, UserWriteError> {
+    let update = update.into_inner();
+    let changes = UserChanges {
+        email: update
+            .email
+            .as_deref()
+            .map(parse_email)
+            .transpose()?
+            .map(|email| email.to_string()),
+        given_name: update.given_name.map(|name| name.trim().to_string()),
+        family_name: update.family_name.map(|name| name.trim().to_string()),
+    };
+    match users.update_user(id, changes).await? {
+        Some(user) => {
+            audit.record(admin.id, AuditAction::UpdateUser, id);
+            Ok(Json(user))
+        }
+        None => Err(UserWriteError::Status(Status::NotFound)),
+    }
+}